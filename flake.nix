@@ -41,6 +41,9 @@
                 self.packages.${pkgs.system}.default
               }/bin/clipboard-sync --hide-timestamp --log-level debug";
               serviceConfig.Restart = "on-failure";
+              serviceConfig.Type = "notify";
+              serviceConfig.NotifyAccess = "all";
+              serviceConfig.WatchdogSec = 30;
             };
           };
         };
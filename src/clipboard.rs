@@ -1,47 +1,75 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::{env, io::Read, process::Command, thread::sleep, time::Duration};
-use terminal_clipboard::Clipboard as TerminalClipboard;
-use wl_clipboard_rs::copy::{MimeType as CopyMimeType, Options, Source};
-use wl_clipboard_rs::paste::{
-    get_contents, ClipboardType, Error as PasteError, MimeType as PasteMimeType, Seat,
-};
-
-use crate::error::{Generify, MyResult, Standardize};
-
-pub trait Clipboard: std::fmt::Debug {
-    fn display(&self) -> String;
-    fn get(&self) -> MyResult<String>;
-    fn set(&self, value: &str) -> MyResult<()>;
-    fn watch(&self) -> MyResult<String> {
-        let start = self.get()?;
-        loop {
-            let now = self.get()?;
-            if now != start {
-                return Ok(now);
-            }
-            sleep(Duration::from_millis(1000));
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::content::{readable_mime_types, text_aliases, ClipboardContent};
+use crate::error::{MyError, MyResult};
+use crate::{osc52, peer, tmux, wayland, x11};
+
+/// Which of a display's selections a clipboard reads and writes. Each one is
+/// synchronized as an independent channel.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Selection {
+    /// The regular clipboard, used for copy and paste.
+    Clipboard,
+    /// The primary selection, used for middle-click paste.
+    Primary,
+}
+
+impl std::fmt::Display for Selection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Selection::Clipboard => write!(f, "CLIPBOARD"),
+            Selection::Primary => write!(f, "PRIMARY"),
         }
     }
 }
 
+/// Every clipboard addresses its display explicitly, so clipboards can be used
+/// from any thread, and several of them at once.
+pub trait Clipboard: std::fmt::Debug + Send + Sync {
+    fn display(&self) -> String;
+    fn get(&self) -> MyResult<ClipboardContent>;
+    fn set(&self, value: &ClipboardContent) -> MyResult<()>;
+
+    /// Start sending this clipboard's display name to `notify` whenever its
+    /// contents change. Returns false if the clipboard can't report changes by
+    /// itself, in which case it needs to be polled.
+    fn watch(&self, _notify: Sender<String>) -> MyResult<bool> {
+        Ok(false)
+    }
+}
+
 impl<T: Clipboard> Clipboard for Box<T> {
-    fn get(&self) -> MyResult<String> {
+    fn get(&self) -> MyResult<ClipboardContent> {
         (**self).get()
     }
 
-    fn set(&self, value: &str) -> MyResult<()> {
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
         (**self).set(value)
     }
 
     fn display(&self) -> String {
         (**self).display()
     }
+
+    fn watch(&self, notify: Sender<String>) -> MyResult<bool> {
+        (**self).watch(notify)
+    }
 }
 
 #[derive(Debug)]
 pub struct WlrClipboard {
     pub display: String,
+    pub selection: Selection,
 }
 
 impl Clipboard for WlrClipboard {
@@ -49,87 +77,105 @@ impl Clipboard for WlrClipboard {
         self.display.clone()
     }
 
-    fn get(&self) -> MyResult<String> {
-        env::set_var("WAYLAND_DISPLAY", self.display.clone());
-        let result = get_contents(
-            ClipboardType::Regular,
-            Seat::Unspecified,
-            PasteMimeType::Text,
-        );
+    fn get(&self) -> MyResult<ClipboardContent> {
+        let mut content = wayland::get(&self.display, self.selection)?;
+        content.remove_text_aliases();
 
-        match result {
-            Ok((mut pipe, _)) => {
-                let mut contents = vec![];
-                pipe.read_to_end(&mut contents)?;
-                Ok(String::from_utf8_lossy(&contents).to_string())
-            }
-
-            Err(PasteError::NoSeats)
-            | Err(PasteError::ClipboardEmpty)
-            | Err(PasteError::NoMimeType) => Ok("".to_string()),
+        Ok(content)
+    }
 
-            Err(err) => Err(err)?,
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
+        let mut sources = value
+            .iter()
+            .map(|(mime_type, data)| (mime_type.to_string(), data.to_vec()))
+            .collect::<Vec<_>>();
+        if let Some(text) = value.text() {
+            for alias in text_aliases() {
+                if value.get(alias).is_none() {
+                    sources.push((alias.to_string(), text.as_bytes().to_vec()));
+                }
+            }
         }
-    }
 
-    fn set(&self, value: &str) -> MyResult<()> {
-        env::set_var("WAYLAND_DISPLAY", self.display.clone());
-        let opts = Options::new();
-        let result = std::panic::catch_unwind(|| {
-            opts.copy(
-                Source::Bytes(value.to_string().into_bytes().into()),
-                CopyMimeType::Text,
-            )
-        });
+        wayland::set(&self.display, self.selection, sources)
+    }
 
-        Ok(result.standardize().generify()??)
+    fn watch(&self, notify: Sender<String>) -> MyResult<bool> {
+        wayland::watch_selection(&self.display, self.selection, notify)?;
+        Ok(true)
     }
 }
 
+/// Uses the wl-paste and wl-copy commands. wl-copy can only offer a single mime
+/// type, so this only offers the text, or the first mime type if there is no
+/// text.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct CommandClipboard {
     display: String,
+    selection: Selection,
 }
 
-impl Clipboard for CommandClipboard {
-    fn display(&self) -> String {
-        self.display.clone()
-    }
-
-    fn get(&self) -> MyResult<String> {
-        env::set_var("WAYLAND_DISPLAY", self.display.clone());
-        let out = Command::new("wl-paste").output()?.stdout;
-        Ok(String::from_utf8_lossy(&out).trim().to_string())
-    }
-
-    fn set(&self, value: &str) -> MyResult<()> {
-        env::set_var("WAYLAND_DISPLAY", self.display.clone());
-        Command::new("wl-copy").arg(value).spawn()?;
-
-        Ok(())
+impl CommandClipboard {
+    fn command(&self, program: &str) -> Command {
+        let mut command = Command::new(program);
+        command.env("WAYLAND_DISPLAY", &self.display);
+        if self.selection == Selection::Primary {
+            command.arg("--primary");
+        }
+        command
     }
 }
 
-#[derive(Debug)]
-pub struct ArClipboard {
-    display: String,
-}
-
-impl Clipboard for ArClipboard {
+impl Clipboard for CommandClipboard {
     fn display(&self) -> String {
         self.display.clone()
     }
 
-    fn get(&self) -> MyResult<String> {
-        env::set_var("WAYLAND_DISPLAY", self.display.clone());
-        let mut clipboard = arboard::Clipboard::new()?;
-        Ok(clipboard.get_text().unwrap_or_default())
+    fn get(&self) -> MyResult<ClipboardContent> {
+        let out = self
+            .command("wl-paste")
+            .arg("--list-types")
+            .output()?
+            .stdout;
+        let offered = String::from_utf8_lossy(&out)
+            .lines()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let mut content = ClipboardContent::new();
+        for (mime_type, store_as) in readable_mime_types(&offered) {
+            let out = self
+                .command("wl-paste")
+                .args(["--no-newline", "--type", &mime_type])
+                .output()?;
+            if out.status.success() {
+                content.insert(store_as, out.stdout);
+            }
+        }
+        content.remove_text_aliases();
+
+        Ok(content)
     }
 
-    fn set(&self, value: &str) -> MyResult<()> {
-        env::set_var("WAYLAND_DISPLAY", self.display.clone());
-        let mut clipboard = arboard::Clipboard::new()?;
-        clipboard.set_text(value.into())?;
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
+        let text = value.text();
+        let (mime_type, data) = match (&text, value.iter().next()) {
+            (Some(text), _) => ("text/plain", text.as_bytes()),
+            (None, Some(first)) => first,
+            (None, None) => {
+                self.command("wl-copy").arg("--clear").status()?;
+                return Ok(());
+            }
+        };
+        let mut child = self
+            .command("wl-copy")
+            .args(["--type", mime_type])
+            .stdin(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(data)?;
+        }
+        child.wait()?;
 
         Ok(())
     }
@@ -137,20 +183,17 @@ impl Clipboard for ArClipboard {
 
 pub struct X11Clipboard {
     display: String,
+    selection: Selection,
     backend: X11Backend,
+    /// stops watching for changes when the clipboard is dropped
+    watcher: Mutex<Option<x11::SelectionWatcher>>,
 }
 
 #[derive(Clone)]
-pub struct X11Backend(Rc<RefCell<terminal_clipboard::X11Clipboard>>);
+pub struct X11Backend(Arc<x11::X11Selection>);
 impl X11Backend {
-    /// try to only call this once because repeated initializations may not work.
-    /// i started seeing timeouts/errors after 4
-    pub fn new(display: &str) -> MyResult<Self> {
-        // let backend = stdio! { terminal_clipboard::X11Clipboard::new() }.standardize()?;
-        env::set_var("DISPLAY", display);
-        let backend = terminal_clipboard::X11Clipboard::new().standardize()?;
-
-        Ok(Self(Rc::new(RefCell::new(backend))))
+    pub fn new(display: &str, selection: Selection) -> MyResult<Self> {
+        Ok(Self(Arc::new(x11::X11Selection::new(display, selection)?)))
     }
 }
 
@@ -158,21 +201,20 @@ impl std::fmt::Debug for X11Clipboard {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("X11Clipboard")
             .field("display", &self.display)
+            .field("selection", &self.selection)
             .finish()
     }
 }
 
 impl X11Clipboard {
-    pub fn new(display: String) -> MyResult<Self> {
+    pub fn new(display: String, selection: Selection) -> MyResult<Self> {
         Ok(Self {
-            backend: X11Backend::new(&display)?,
+            backend: X11Backend::new(&display, selection)?,
             display,
+            selection,
+            watcher: Mutex::new(None),
         })
     }
-
-    // fn backend(&self) -> X11Backend {
-    //     X11Backend::new_str(&self.display).unwrap()
-    // }
 }
 
 impl Clipboard for X11Clipboard {
@@ -180,28 +222,134 @@ impl Clipboard for X11Clipboard {
         self.display.clone()
     }
 
-    fn get(&self) -> MyResult<String> {
-        // env::set_var("DISPLAY", self.display.clone());
-        Ok(self
-            .backend
-            .0
-            .try_borrow()?
-            .get_string()
-            .unwrap_or_default())
+    fn get(&self) -> MyResult<ClipboardContent> {
+        let mut content = self.backend.0.get()?;
+        content.remove_text_aliases();
+        Ok(content)
     }
 
-    fn set(&self, value: &str) -> MyResult<()> {
-        // env::set_var("DISPLAY", self.display.clone());
-        self.backend
-            .0
-            .try_borrow_mut()?
-            .set_string(value)
-            .standardize()?;
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
+        self.backend.0.set(value)
+    }
 
-        Ok(())
+    fn watch(&self, notify: Sender<String>) -> MyResult<bool> {
+        let watcher = x11::watch_selection(&self.display, self.selection, notify)?;
+        *self.watcher.lock()? = Some(watcher);
+        Ok(true)
     }
 }
 
+/// Gives up on any call to the inner clipboard that takes longer than
+/// `timeout`, so a display that hangs can't block the others.
+///
+/// Each call runs on its own thread, which is left behind when the call times
+/// out. Until that thread finishes, calls fail immediately instead of piling up
+/// more threads that are stuck on the same display.
+pub struct Deadline {
+    inner: Arc<dyn Clipboard>,
+    timeout: Duration,
+    /// set while a call is running, including one that timed out
+    busy: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    pub fn new(inner: impl Clipboard + 'static, timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            timeout,
+            busy: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn Clipboard) -> MyResult<T> + Send + 'static,
+    ) -> MyResult<T> {
+        let display = self.inner.display();
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return Err(MyError::Unresponsive(display));
+        }
+        let inner = self.inner.clone();
+        let idle = Idle(self.busy.clone());
+        with_deadline(&display, self.timeout, move || {
+            let _idle = idle;
+            f(&*inner)
+        })
+    }
+}
+
+/// Clears the busy flag when a call finishes, even if it panics.
+struct Idle(Arc<AtomicBool>);
+
+impl Drop for Idle {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// The kind of clipboard that a display name refers to.
+pub fn backend(display: &str) -> &'static str {
+    if display == peer::DISPLAY {
+        "peers"
+    } else if display.starts_with(':') {
+        "x11"
+    } else if display.starts_with("wayland") {
+        "wayland"
+    } else if display.starts_with(tmux::PREFIX) {
+        "tmux"
+    } else if display.starts_with(osc52::PREFIX) {
+        "osc52"
+    } else {
+        "command"
+    }
+}
+
+/// Runs `f` on its own thread, and waits at most `timeout` for it to finish.
+pub fn with_deadline<T: Send + 'static>(
+    display: &str,
+    timeout: Duration,
+    f: impl FnOnce() -> MyResult<T> + Send + 'static,
+) -> MyResult<T> {
+    let (tx, rx) = sync_channel(1);
+    thread::Builder::new()
+        .name(format!("call {display}"))
+        .spawn(move || drop(tx.send(f())))?;
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(MyError::Timeout {
+            display: display.to_string(),
+            timeout,
+        }),
+        Err(RecvTimeoutError::Disconnected) => Err(MyError::CallPanicked(display.to_string())),
+    }
+}
+
+impl std::fmt::Debug for Deadline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl Clipboard for Deadline {
+    fn display(&self) -> String {
+        self.inner.display()
+    }
+
+    fn get(&self) -> MyResult<ClipboardContent> {
+        self.call(|c| c.get())
+    }
+
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
+        let value = value.clone();
+        self.call(move |c| c.set(&value))
+    }
+
+    fn watch(&self, notify: Sender<String>) -> MyResult<bool> {
+        self.call(move |c| c.watch(notify))
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct HybridClipboard<G: Clipboard, S: Clipboard> {
     getter: G,
@@ -224,11 +372,40 @@ impl<G: Clipboard, S: Clipboard> Clipboard for HybridClipboard<G, S> {
         self.getter.display()
     }
 
-    fn get(&self) -> MyResult<String> {
+    fn get(&self) -> MyResult<ClipboardContent> {
         self.getter.get()
     }
 
-    fn set(&self, value: &str) -> MyResult<()> {
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
         self.setter.set(value)
     }
 }
+
+#[test]
+fn test() {
+    /// takes a second to read
+    #[derive(Debug)]
+    struct Slow;
+    impl Clipboard for Slow {
+        fn display(&self) -> String {
+            ":9".to_string()
+        }
+        fn get(&self) -> MyResult<ClipboardContent> {
+            thread::sleep(Duration::from_secs(1));
+            Ok(ClipboardContent::from_text("slow"))
+        }
+        fn set(&self, _: &ClipboardContent) -> MyResult<()> {
+            Ok(())
+        }
+    }
+
+    let patient = Deadline::new(Slow, Duration::from_secs(5));
+    assert_eq!(Some("slow".to_string()), patient.get().unwrap().text());
+    let hasty = Deadline::new(Slow, Duration::from_millis(10));
+    assert!(matches!(hasty.get(), Err(MyError::Timeout { .. })));
+    assert!(matches!(hasty.get(), Err(MyError::Unresponsive(_))));
+    assert!(hasty.get().unwrap_err().is_timeout());
+    hasty.set(&ClipboardContent::new()).unwrap_err();
+    thread::sleep(Duration::from_millis(1500));
+    assert!(hasty.set(&ClipboardContent::new()).is_ok());
+}
@@ -0,0 +1,445 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::{MyError, MyResult};
+use crate::log;
+
+/// Settings loaded from the config file. Every field is optional in the file,
+/// and the command line flags take precedence over it.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: log::Level,
+    pub hide_timestamp: bool,
+    pub log_format: log::Format,
+    pub log_target: log::Target,
+    pub run_forked: bool,
+    pub log_clipboard_contents: bool,
+    pub primary: bool,
+
+    /// how often to check clipboards that can't report their own changes
+    pub poll_interval_ms: u64,
+    /// how long to wait after syncing before looking for the next change
+    pub settle_delay_ms: u64,
+    /// when running forked, the child is restarted this often, regardless of
+    /// its health. 0 disables these restarts.
+    pub restart_interval_secs: u64,
+    /// when running forked, the child is restarted when a sync loop hasn't
+    /// shown that it's running for this long. 0 disables the check.
+    pub max_heartbeat_age_secs: u64,
+    /// when running forked, the child is restarted when a display stops
+    /// responding this many times in a minute. 0 disables the check.
+    pub max_display_errors_per_minute: usize,
+    /// when running forked, the child is restarted when it has this many
+    /// files open. 0 disables the check.
+    pub max_open_files: usize,
+    /// when running forked, the child is restarted when its resident memory
+    /// reaches this many MiB. 0 disables the check.
+    pub max_memory_mb: u64,
+    /// how much recent error "pain" is tolerated before giving up. each error
+    /// adds 1, which decays with a half-life of 1 minute.
+    pub pain_threshold: f64,
+    /// how long a single read or write of a display's clipboard may take.
+    /// displays that take longer are skipped until they respond again.
+    pub operation_timeout_ms: u64,
+
+    /// which wayland-N displays to look for
+    pub wayland_displays: DisplayRange,
+    /// which :N x11 displays to look for
+    pub x11_displays: DisplayRange,
+    /// settings for specific displays, keyed by name, like "wayland-1" or ":0"
+    pub displays: BTreeMap<String, DisplayConfig>,
+    /// restrict where changes from specific displays are copied
+    pub rules: Vec<Rule>,
+
+    /// groups of displays that share a clipboard, isolated from the others
+    pub groups: BTreeMap<String, GroupConfig>,
+    /// the group for displays that aren't in any of the `groups`
+    pub default_group: String,
+    /// don't sync displays that aren't in any of the `groups`
+    pub ignore_ungrouped: bool,
+
+    /// where to save the clipboard history. defaults to
+    /// $XDG_DATA_HOME/clipboard-sync/history
+    pub history_dir: Option<PathBuf>,
+    /// how many values to remember. 0 disables the history.
+    pub history_max_entries: usize,
+    /// values are forgotten once they are this old
+    pub history_max_age_secs: u64,
+    /// the oldest values are forgotten when the history is bigger than this
+    pub history_max_bytes: u64,
+
+    /// other machines to sync with over the network
+    pub peers: PeersConfig,
+    /// ttys of terminal emulators to sync with OSC 52 escape sequences, like
+    /// "/dev/pts/3". each one is a display named "osc52:" followed by the path.
+    pub terminals: Vec<PathBuf>,
+    /// ask the terminals for their clipboard with OSC 52 queries, so what's
+    /// copied in them is synced too. otherwise they're only copied to.
+    pub query_terminals: bool,
+
+    /// publish the org.clipboardsync.Daemon service on the d-bus session bus
+    pub dbus: bool,
+    /// include the contents in ClipboardChanged signals, except for secrets
+    pub dbus_signal_content: bool,
+
+    /// what to do with values that a password manager marked as secret
+    pub secret_policy: SecretPolicy,
+    /// with the "expire" policy, secrets are cleared from every display after
+    /// this long
+    pub secret_expiry_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: log::Level::default(),
+            hide_timestamp: false,
+            log_format: log::Format::Text,
+            log_target: log::Target::Auto,
+            run_forked: cfg!(not(debug_assertions)),
+            log_clipboard_contents: false,
+            primary: false,
+            poll_interval_ms: 200,
+            settle_delay_ms: 100,
+            restart_interval_secs: 0,
+            max_heartbeat_age_secs: 60,
+            max_display_errors_per_minute: 10,
+            max_open_files: 512,
+            max_memory_mb: 256,
+            pain_threshold: 5.0,
+            operation_timeout_ms: 5000,
+            wayland_displays: DisplayRange::default(),
+            x11_displays: DisplayRange::default(),
+            displays: BTreeMap::new(),
+            rules: vec![],
+            groups: BTreeMap::new(),
+            default_group: "default".to_string(),
+            ignore_ungrouped: false,
+            history_dir: None,
+            history_max_entries: 100,
+            history_max_age_secs: 7 * 24 * 60 * 60,
+            history_max_bytes: 64 * 1024 * 1024,
+            peers: PeersConfig::default(),
+            terminals: vec![],
+            query_terminals: false,
+            dbus: true,
+            dbus_signal_content: false,
+            secret_policy: SecretPolicy::Expire,
+            secret_expiry_secs: 30,
+        }
+    }
+}
+
+/// How to sync values that a password manager marked as secret. Secrets are
+/// never saved to the history or logged, regardless of the policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretPolicy {
+    /// leave secrets on the display they were copied in
+    Skip,
+    /// sync secrets, then clear them from every display after a while
+    Expire,
+    /// sync secrets like any other value
+    Sync,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    /// never sync this display
+    pub ignore: bool,
+    /// check this display for changes every poll interval instead of asking
+    /// it to report its changes
+    pub poll: bool,
+    /// which directions to copy between this display and the others
+    pub role: Role,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// changes are copied both to and from the display
+    #[default]
+    Bidirectional,
+    /// changes are copied from the display to others, but never to it
+    SourceOnly,
+    /// changes are copied to the display, but never from it
+    SinkOnly,
+}
+
+impl Role {
+    pub fn sends(self) -> bool {
+        self != Role::SinkOnly
+    }
+
+    pub fn receives(self) -> bool {
+        self != Role::SourceOnly
+    }
+}
+
+/// Changes in the `from` display are only copied to the `to` displays. When
+/// there are several rules for the same display, it's copied to all of their
+/// displays. Displays without any rules are copied everywhere.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    /// the names of the displays in the group, like "wayland-1" or ":0"
+    pub displays: Vec<String>,
+}
+
+/// Syncs with clipboard-sync on other machines. Every peer is represented by a
+/// single display named "peers", which can be configured like other displays.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeersConfig {
+    /// accept connections from peers on this address, like "0.0.0.0:7878"
+    pub listen: Option<String>,
+    /// the addresses of peers to connect to, like "laptop.local:7878"
+    pub connect: Vec<String>,
+    /// a file with the secret that every peer shares, which encrypts and
+    /// authenticates the connections
+    pub key_file: Option<PathBuf>,
+}
+
+impl PeersConfig {
+    pub fn enabled(&self) -> bool {
+        self.listen.is_some() || !self.connect.is_empty()
+    }
+}
+
+/// An inclusive range of display numbers, written as `[first, last]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "[u8; 2]")]
+pub struct DisplayRange {
+    pub first: u8,
+    pub last: u8,
+}
+
+impl Default for DisplayRange {
+    fn default() -> Self {
+        Self {
+            first: 0,
+            last: u8::MAX - 1,
+        }
+    }
+}
+
+impl TryFrom<[u8; 2]> for DisplayRange {
+    type Error = String;
+
+    fn try_from([first, last]: [u8; 2]) -> Result<Self, Self::Error> {
+        if first > last {
+            return Err(format!("display range [{first}, {last}] is empty"));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+impl DisplayRange {
+    pub fn iter(&self) -> impl Iterator<Item = u8> {
+        self.first..=self.last
+    }
+
+    pub fn contains(&self, n: u8) -> bool {
+        (self.first..=self.last).contains(&n)
+    }
+}
+
+impl Config {
+    /// Reads the config file at `path`, or the default path if there is no
+    /// `path`. It's fine for the default file to be missing, in which case
+    /// the defaults are used.
+    pub fn load(path: Option<&Path>) -> MyResult<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).map_err(|source| MyError::ConfigParse { path, source }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && !required => {
+                Ok(Self::default())
+            }
+            Err(source) => Err(MyError::ConfigRead { path, source }),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        let config: Self = toml::from_str(text)?;
+        if config.peers.enabled() && config.peers.key_file.is_none() {
+            return Err(serde::de::Error::custom("peers need a key_file"));
+        }
+        let mut grouped = BTreeMap::new();
+        for (group, group_config) in &config.groups {
+            for display in &group_config.displays {
+                if let Some(other) = grouped.insert(display, group) {
+                    return Err(serde::de::Error::custom(format!(
+                        "display {display:?} is in both group {other:?} and group {group:?}"
+                    )));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn display(&self, name: &str) -> DisplayConfig {
+        self.displays.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Whether a change in the `from` display may be copied to the `to`
+    /// display, according to their roles and the rules.
+    pub fn may_sync(&self, from: &str, to: &str) -> bool {
+        if from == to {
+            return true;
+        }
+        if !self.display(from).role.sends() || !self.display(to).role.receives() {
+            return false;
+        }
+        let mut rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.from == from)
+            .peekable();
+        rules.peek().is_none() || rules.any(|rule| rule.to.iter().any(|d| d == to))
+    }
+
+    /// The group that a display belongs to, if it should be synced.
+    pub fn group_of(&self, display: &str) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|(_, group)| group.displays.iter().any(|d| d == display))
+            .map(|(name, _)| name.as_str())
+            .or((!self.ignore_ungrouped).then_some(self.default_group.as_str()))
+    }
+
+    /// Every group that may contain displays.
+    pub fn group_names(&self) -> BTreeSet<String> {
+        let mut names = self.groups.keys().cloned().collect::<BTreeSet<_>>();
+        if !self.ignore_ungrouped {
+            names.insert(self.default_group.clone());
+        }
+        names
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    pub fn operation_timeout(&self) -> Duration {
+        Duration::from_millis(self.operation_timeout_ms)
+    }
+
+    pub fn settle_delay(&self) -> Duration {
+        Duration::from_millis(self.settle_delay_ms)
+    }
+
+    pub fn secret_expiry(&self) -> Duration {
+        Duration::from_secs(self.secret_expiry_secs)
+    }
+}
+
+/// $XDG_CONFIG_HOME/clipboard-sync/config.toml, where XDG_CONFIG_HOME
+/// defaults to ~/.config
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?;
+
+    Some(config_home.join("clipboard-sync").join("config.toml"))
+}
+
+#[test]
+fn test() {
+    assert_eq!(Config::default(), Config::parse("").unwrap());
+
+    let config = Config::parse(
+        r#"
+log_level = "trace"
+log_format = "json"
+poll_interval_ms = 50
+secret_policy = "skip"
+x11_displays = [0, 9]
+
+[displays."wayland-1"]
+ignore = true
+
+[displays.":0"]
+poll = true
+"#,
+    )
+    .unwrap();
+    assert_eq!(log::Level::Trace, config.log_level);
+    assert_eq!(log::Format::Json, config.log_format);
+    assert_eq!(Duration::from_millis(50), config.poll_interval());
+    assert_eq!(SecretPolicy::Skip, config.secret_policy);
+    assert_eq!(10, config.x11_displays.iter().count());
+    assert!(config.display("wayland-1").ignore);
+    assert!(config.display(":0").poll && !config.display(":0").ignore);
+    assert_eq!(DisplayConfig::default(), config.display(":1"));
+    assert_eq!(Some("default"), config.group_of(":1"));
+    assert_eq!(
+        BTreeSet::from(["default".to_string()]),
+        config.group_names()
+    );
+
+    let config = Config::parse(
+        r#"
+[displays.":5"]
+role = "sink-only"
+[displays.":6"]
+role = "source-only"
+
+[[rules]]
+from = "wayland-0"
+to = [":5"]
+"#,
+    )
+    .unwrap();
+    assert!(config.may_sync("wayland-0", ":5"));
+    assert!(!config.may_sync("wayland-0", ":0"));
+    assert!(!config.may_sync(":5", ":0"));
+    assert!(config.may_sync(":6", ":0"));
+    assert!(config.may_sync(":6", ":5"));
+    assert!(!config.may_sync(":0", ":6"));
+    assert!(config.may_sync(":6", ":6"));
+
+    let config = Config::parse(
+        r#"
+ignore_ungrouped = true
+[groups.work]
+displays = [":5", "wayland-2"]
+[groups.personal]
+displays = [":0"]
+"#,
+    )
+    .unwrap();
+    assert_eq!(Some("work"), config.group_of("wayland-2"));
+    assert_eq!(Some("personal"), config.group_of(":0"));
+    assert_eq!(None, config.group_of(":1"));
+    assert_eq!(2, config.group_names().len());
+    let err = Config::parse("[groups.a]\ndisplays = [\":0\"]\n[groups.b]\ndisplays = [\":0\"]")
+        .unwrap_err();
+    assert!(err.to_string().contains("both"), "{err}");
+
+    let err = Config::parse("[peers]\nconnect = [\"laptop:7878\"]").unwrap_err();
+    assert!(err.to_string().contains("key_file"), "{err}");
+
+    let err = Config::parse("primary = true\nwayland_displays = [3, 1]\n").unwrap_err();
+    assert!(err.to_string().contains("line 2"), "{err}");
+    let err = Config::parse("\n\npoll_interval = 5\n").unwrap_err();
+    assert!(err.to_string().contains("line 3"), "{err}");
+}
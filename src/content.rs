@@ -0,0 +1,224 @@
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine};
+use std::collections::BTreeMap;
+
+/// The mime type used to store plain text, and the one that's preferred when
+/// the contents are interpreted as text.
+pub const TEXT_MIME_TYPE: &str = "text/plain;charset=utf-8";
+
+/// Other names for plain text in order of preference. The x11 names are
+/// offered by many applications, including some wayland clients, as aliases
+/// for a text/plain mime type.
+const TEXT_ALIASES: [&str; 6] = [
+    "text/plain",
+    "UTF8_STRING",
+    "STRING",
+    "TEXT",
+    "COMPOUND_TEXT",
+    "text/plain;charset=UTF-8",
+];
+
+/// Password managers like KeePassXC offer this mime type, with the data
+/// "secret", to mark the contents as a password.
+const PASSWORD_MANAGER_HINT: &str = "x-kde-passwordManagerHint";
+
+/// x11 targets that describe the selection rather than holding its contents.
+const META_TARGETS: [&str; 7] = [
+    "TARGETS",
+    "TIMESTAMP",
+    "MULTIPLE",
+    "SAVE_TARGETS",
+    "DELETE",
+    "INSERT_SELECTION",
+    "INSERT_PROPERTY",
+];
+
+/// Everything offered by a clipboard: the data for each mime type that the
+/// clipboard's owner is able to provide.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct ClipboardContent {
+    data: BTreeMap<String, Vec<u8>>,
+}
+
+impl ClipboardContent {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_text(text: &str) -> Self {
+        let mut content = Self::new();
+        content.insert(TEXT_MIME_TYPE, text.as_bytes().to_vec());
+        content
+    }
+
+    pub fn insert(&mut self, mime_type: impl Into<String>, data: Vec<u8>) {
+        self.data.insert(mime_type.into(), data);
+    }
+
+    pub fn get(&self, mime_type: &str) -> Option<&[u8]> {
+        self.data.get(mime_type).map(|v| v.as_slice())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.data.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn mime_types(&self) -> Vec<String> {
+        self.data.keys().cloned().collect()
+    }
+
+    /// The total number of bytes of data across all mime types.
+    pub fn size(&self) -> usize {
+        self.data.values().map(|data| data.len()).sum()
+    }
+
+    /// Whether a password manager marked the contents as secret.
+    pub fn is_secret(&self) -> bool {
+        self.get(PASSWORD_MANAGER_HINT) == Some(b"secret")
+    }
+
+    /// The contents interpreted as plain text, if any text is offered.
+    pub fn text(&self) -> Option<String> {
+        std::iter::once(TEXT_MIME_TYPE)
+            .chain(TEXT_ALIASES)
+            .find_map(|mime| self.get(mime))
+            .or_else(|| {
+                self.data
+                    .iter()
+                    .find(|(mime, _)| mime.starts_with("text/plain"))
+                    .map(|(_, data)| data.as_slice())
+            })
+            .map(|data| String::from_utf8_lossy(data).to_string())
+    }
+
+    /// Removes other names for the text that hold the same data as the text,
+    /// since they are offered automatically whenever there is text.
+    pub fn remove_text_aliases(&mut self) {
+        if let Some(text) = self.data.get(TEXT_MIME_TYPE).cloned() {
+            self.data
+                .retain(|mime, data| !(TEXT_ALIASES.contains(&mime.as_str()) && *data == text));
+        }
+    }
+}
+
+/// Decides which of the mime types offered by a clipboard need to be read to
+/// capture its contents, and the mime type to store each one under. This
+/// skips x11 meta targets, and only reads the x11 names for text when there
+/// is no text/plain alternative, so the same copy produces the same
+/// ClipboardContent regardless of which backend it's read from.
+pub fn readable_mime_types(offered: &[String]) -> Vec<(String, String)> {
+    let has_text = offered.iter().any(|m| m.starts_with("text/plain"));
+    let x11_text = TEXT_ALIASES[1..5]
+        .iter()
+        .find(|alias| !has_text && offered.iter().any(|m| m == *alias));
+    let mut readable = vec![];
+    for mime in offered {
+        if META_TARGETS.contains(&mime.as_str()) || mime.is_empty() {
+            continue;
+        }
+        if TEXT_ALIASES.contains(&mime.as_str()) && !mime.starts_with("text/plain") {
+            if x11_text == Some(&mime.as_str()) {
+                readable.push((mime.clone(), TEXT_MIME_TYPE.to_string()));
+            }
+        } else {
+            readable.push((mime.clone(), mime.clone()));
+        }
+    }
+    readable
+}
+
+/// The names that text should be offered under in addition to its own mime
+/// type, for the benefit of clients that only understand some of them.
+pub fn text_aliases() -> &'static [&'static str] {
+    &TEXT_ALIASES[..4]
+}
+
+/// Doesn't show the data because clipboard contents are sensitive.
+impl std::fmt::Debug for ClipboardContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.data.iter().map(|(k, v)| (k, v.len())))
+            .finish()
+    }
+}
+
+/// Shows the text if there is any, otherwise a summary of the mime types.
+/// Only use this with log::sensitive. Secrets are never shown.
+impl std::fmt::Display for ClipboardContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_secret() {
+            return write!(f, "<secret>");
+        }
+        match self.text() {
+            Some(text) => write!(f, "{text}"),
+            None => write!(f, "{self:?}"),
+        }
+    }
+}
+
+/// Stored as a map from each mime type to its base64 encoded data.
+impl serde::Serialize for ClipboardContent {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter().map(|(mime, data)| (mime, BASE64.encode(data))))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ClipboardContent {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = BTreeMap::<String, String>::deserialize(deserializer)?;
+        let mut content = Self::new();
+        for (mime, data) in encoded {
+            let data = BASE64.decode(data).map_err(serde::de::Error::custom)?;
+            content.insert(mime, data);
+        }
+        Ok(content)
+    }
+}
+
+#[test]
+fn test() {
+    let offered = |types: &[&str]| types.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let pairs = |types: &[(&str, &str)]| {
+        types
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        pairs(&[("UTF8_STRING", TEXT_MIME_TYPE)]),
+        readable_mime_types(&offered(&["TARGETS", "TEXT", "UTF8_STRING", "STRING"]))
+    );
+    assert_eq!(
+        pairs(&[("text/plain", "text/plain"), ("image/png", "image/png")]),
+        readable_mime_types(&offered(&[
+            "UTF8_STRING",
+            "text/plain",
+            "TIMESTAMP",
+            "image/png"
+        ]))
+    );
+
+    let mut content = ClipboardContent::from_text("hello");
+    content.insert("text/plain", b"hello".to_vec());
+    content.insert("STRING", b"goodbye".to_vec());
+    content.remove_text_aliases();
+    let mut expected = ClipboardContent::from_text("hello");
+    expected.insert("STRING", b"goodbye".to_vec());
+    assert_eq!(expected, content);
+    assert_eq!(Some("hello".to_string()), content.text());
+
+    let json = serde_json::to_string(&content).unwrap();
+    assert_eq!(
+        r#"{"STRING":"Z29vZGJ5ZQ==","text/plain;charset=utf-8":"aGVsbG8="}"#,
+        json
+    );
+    assert_eq!(content, serde_json::from_str(&json).unwrap());
+
+    assert!(!content.is_secret());
+    content.insert(PASSWORD_MANAGER_HINT, b"secret".to_vec());
+    assert!(content.is_secret());
+    assert_eq!("<secret>", content.to_string());
+}
@@ -0,0 +1,756 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clipboard::{Clipboard, Selection};
+use crate::content::ClipboardContent;
+use crate::error::{MyError, MyResult};
+use crate::health::Health;
+use crate::history::{self, History};
+use crate::log;
+
+/// How long a connection may take to send its request or read its response.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A command sent to the running daemon through the control socket. These
+/// are also the cli subcommands, which send the request and print the
+/// response.
+#[derive(clap::Subcommand, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// show what the running daemon is doing
+    Status,
+    /// list the clipboards that are being synced
+    List,
+    /// stop syncing until resumed
+    Pause,
+    /// start syncing again after a pause. the clipboards are rediscovered and
+    /// synced to the contents of the first one that isn't empty.
+    Resume,
+    /// look for clipboards again, for example after starting a new display
+    Rediscover,
+    /// change how much the running daemon logs, until it's restarted
+    SetLogLevel {
+        #[arg(value_enum)]
+        level: log::Level,
+    },
+    /// look through the values that were synced in the past
+    History {
+        #[command(subcommand)]
+        #[serde(flatten)]
+        request: HistoryRequest,
+    },
+}
+
+#[derive(clap::Subcommand, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum HistoryRequest {
+    /// describe every entry in the history, from oldest to newest
+    List,
+    /// show an entry, including its contents, encoded as base64
+    Get { id: u64 },
+    /// copy an entry to every display
+    Restore { id: u64 },
+    /// delete the entire history
+    Clear,
+}
+
+/// The daemon's answer to a Request.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    Status(Status),
+    /// the displays in each group, for each selection
+    List {
+        groups: BTreeMap<String, BTreeMap<Selection, Vec<String>>>,
+    },
+    History {
+        entries: Vec<history::Summary>,
+    },
+    HistoryEntry(history::Entry),
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Status {
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub paused: bool,
+    pub log_level: log::Level,
+    pub groups: BTreeMap<String, BTreeMap<Selection, SelectionStatus>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SelectionStatus {
+    /// how many clipboards are being synced
+    pub clipboards: usize,
+    /// how many changes have been synced since the clipboards were discovered
+    pub syncs: u64,
+    pub last_sync: Option<String>,
+    /// displays that are skipped because they stopped responding
+    #[serde(default)]
+    pub degraded: Vec<String>,
+}
+
+/// The daemon state that can be inspected and changed through the control
+/// socket.
+///
+/// When running forked, the parent process owns a Control that each child
+/// inherits. The child reports changes that should outlive it, like pausing,
+/// back to the parent through a pipe, so the next child starts in the same
+/// state. Its events are sent through the pipe too, if the parent has any
+/// subscribers.
+pub struct Control {
+    started: Instant,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+    /// keyed by group and selection, each of which is synced independently
+    selections: Mutex<BTreeMap<(String, Selection), SelectionState>>,
+    parent: Mutex<Option<File>>,
+    history: Option<History>,
+    subscribers: Mutex<Vec<Sender<Event>>>,
+    /// whether the parent process has subscribers, which the events are sent to
+    forward_events: Mutex<bool>,
+    /// each time a display stopped responding
+    errors: Mutex<Vec<(String, Instant)>>,
+    /// the most recent health report from the child, and when it arrived
+    child_health: Mutex<Option<(Instant, Health)>>,
+}
+
+/// A message from a child process to its parent.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "report", rename_all = "kebab-case")]
+enum Report {
+    /// a change that should survive a restart
+    Request(Request),
+    Health(Health),
+    /// for the parent's subscribers
+    Event(Event),
+}
+
+/// Something that happened in one of the sync loops.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// `content` was copied from `display` to the other clipboards
+    Synced {
+        group: String,
+        selection: Selection,
+        display: String,
+        content: ClipboardContent,
+    },
+    DisplayAdded {
+        group: String,
+        selection: Selection,
+        display: String,
+    },
+    DisplayRemoved {
+        group: String,
+        selection: Selection,
+        display: String,
+    },
+}
+
+#[derive(Default)]
+struct SelectionState {
+    clipboards: Vec<String>,
+    degraded: Vec<String>,
+    syncs: u64,
+    last_sync: Option<DateTime<Local>>,
+    /// what every clipboard contains, as far as the sync loop knows
+    current: Option<ClipboardContent>,
+    rediscover: bool,
+    /// contents to copy to every clipboard, like a history entry
+    restore: Option<ClipboardContent>,
+    /// wakes up the sync loop so it notices pauses and rediscovery requests
+    wake: Option<Sender<String>>,
+    /// when the sync loop last showed that it's still running
+    alive: Option<Instant>,
+}
+
+impl Control {
+    pub fn new(history: Option<History>) -> Self {
+        Self {
+            started: Instant::now(),
+            paused: Mutex::new(false),
+            resumed: Condvar::new(),
+            selections: Mutex::new(BTreeMap::new()),
+            parent: Mutex::new(None),
+            history,
+            subscribers: Mutex::new(vec![]),
+            forward_events: Mutex::new(false),
+            errors: Mutex::new(vec![]),
+            child_health: Mutex::new(None),
+        }
+    }
+
+    /// Receives every Event from now on.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends `event` to every subscriber, including the parent's.
+    fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        drop(subscribers);
+        if *self.forward_events.lock().unwrap() {
+            self.report(Report::Event(event));
+        }
+    }
+
+    /// The contents of `selection` in `group`, if it's being synced.
+    pub fn content(&self, group: &str, selection: Selection) -> Option<ClipboardContent> {
+        let selections = self.selections.lock().unwrap();
+        selections
+            .get(&(group.to_string(), selection))?
+            .current
+            .clone()
+    }
+
+    /// Asks the sync loop to copy `content` to every clipboard of `selection`
+    /// in `group`.
+    pub fn set_content(
+        &self,
+        group: &str,
+        selection: Selection,
+        content: ClipboardContent,
+    ) -> MyResult<()> {
+        if *self.paused.lock()? {
+            return Err(MyError::Paused);
+        }
+        let mut selections = self.selections.lock()?;
+        let state = selections
+            .get_mut(&(group.to_string(), selection))
+            .ok_or_else(|| MyError::NotSynced(group.to_string(), selection))?;
+        state.restore = Some(content);
+        if let Some(wake) = &state.wake {
+            drop(wake.send(String::new()));
+        }
+
+        Ok(())
+    }
+
+    /// Whether every selection has discovered its clipboards, or is paused.
+    pub fn ready(&self) -> bool {
+        self.paused()
+            || self
+                .selections
+                .lock()
+                .unwrap()
+                .values()
+                .all(|state| state.alive.is_some())
+    }
+
+    /// The selections whose sync loop hasn't shown that it's running within
+    /// `max_age`, as "group selection". Nothing is stalled while paused.
+    pub fn stalled(&self, max_age: Duration) -> Vec<String> {
+        if self.paused() {
+            return vec![];
+        }
+        self.heartbeats()
+            .into_iter()
+            .filter(|(_, age)| age.unwrap_or(Duration::MAX) > max_age)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// How long ago each sync loop showed that it's still running, keyed by
+    /// "group selection", or None if it hasn't discovered its clipboards.
+    pub fn heartbeats(&self) -> BTreeMap<String, Option<Duration>> {
+        let selections = self.selections.lock().unwrap();
+        selections
+            .iter()
+            .map(|((group, selection), state)| {
+                let age = state.alive.map(|alive| alive.elapsed());
+                (format!("{group} {selection}"), age)
+            })
+            .collect()
+    }
+
+    /// How many times each display stopped responding within `window`.
+    pub fn display_errors(&self, window: Duration) -> BTreeMap<String, usize> {
+        let mut errors = self.errors.lock().unwrap();
+        errors.retain(|(_, time)| time.elapsed() <= window);
+        let mut counts = BTreeMap::new();
+        for (display, _) in errors.iter() {
+            *counts.entry(display.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Every display that is being synced in any group or selection.
+    pub fn displays(&self) -> Vec<String> {
+        let selections = self.selections.lock().unwrap();
+        let mut displays = selections
+            .values()
+            .flat_map(|state| state.clipboards.iter().cloned())
+            .collect::<Vec<_>>();
+        displays.sort();
+        displays.dedup();
+        displays
+    }
+
+    /// Whether syncing is paused.
+    pub fn paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    /// The handle used by the thread that syncs `selection` in `group`.
+    pub fn selection(self: &Arc<Self>, group: &str, selection: Selection) -> SelectionControl {
+        self.selections
+            .lock()
+            .unwrap()
+            .insert((group.to_string(), selection), SelectionState::default());
+        SelectionControl {
+            control: self.clone(),
+            group: group.to_string(),
+            selection,
+        }
+    }
+
+    /// Reports changes that should survive a restart to the parent process
+    /// through `pipe`.
+    pub fn report_to_parent(&self, pipe: File) {
+        *self.parent.lock().unwrap() = Some(pipe);
+        // the subscribers were copied from the parent, and nothing receives
+        // from them in this process, so their events go to the parent instead
+        let subscribers = std::mem::take(&mut *self.subscribers.lock().unwrap());
+        *self.forward_events.lock().unwrap() = !subscribers.is_empty();
+    }
+
+    /// Tells the parent process how healthy this child is.
+    pub fn report_health(&self, health: Health) {
+        self.report(Report::Health(health));
+    }
+
+    /// The most recent health report from the child, and when it arrived.
+    pub fn child_health(&self) -> Option<(Instant, Health)> {
+        self.child_health.lock().unwrap().clone()
+    }
+
+    fn report(&self, report: Report) {
+        if let Some(parent) = &mut *self.parent.lock().unwrap() {
+            if let Err(err) = writeln!(parent, "{}", serde_json::to_string(&report).unwrap()) {
+                log::error!("failed to tell the parent process about {report:?}: {err}");
+            }
+        }
+    }
+
+    /// Applies the changes that a child reports through `pipe` until the
+    /// child exits, and keeps track of its health.
+    pub fn follow_child(self: &Arc<Self>, pipe: File) -> thread::JoinHandle<()> {
+        let control = self.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(pipe).lines() {
+                match line.map(|line| serde_json::from_str(&line)) {
+                    Ok(Ok(Report::Request(request))) => drop(control.apply(request)),
+                    Ok(Ok(Report::Health(health))) => {
+                        *control.child_health.lock().unwrap() = Some((Instant::now(), health))
+                    }
+                    Ok(Ok(Report::Event(event))) => control.publish(event),
+                    Ok(Err(err)) => log::error!("invalid message from child process: {err}"),
+                    Err(err) => {
+                        log::error!("failed to read from child process: {err}");
+                        break;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Answers a request from the control socket.
+    pub fn handle(&self, request: Request) -> Response {
+        match &request {
+            Request::Pause => log::info!("pausing sync"),
+            Request::Resume => log::info!("resuming sync"),
+            Request::Rediscover => log::info!("rediscovering clipboards"),
+            Request::SetLogLevel { level } => log::info!("setting log level to {level:?}"),
+            Request::History {
+                request: HistoryRequest::Restore { id },
+            } => log::info!("restoring clipboard history entry {id}"),
+            Request::History {
+                request: HistoryRequest::Clear,
+            } => log::info!("clearing clipboard history"),
+            Request::Status | Request::List | Request::History { .. } => (),
+        }
+        let response = self.apply(request.clone());
+        let persistent = matches!(
+            request,
+            Request::Pause | Request::Resume | Request::SetLogLevel { .. }
+        );
+        if response == Response::Ok && persistent {
+            self.report(Report::Request(request));
+        }
+        response
+    }
+
+    fn apply(&self, request: Request) -> Response {
+        match request {
+            Request::Status => Response::Status(self.status()),
+            Request::List => Response::List {
+                groups: self.by_group(|state| state.clipboards.clone()),
+            },
+            Request::Pause => {
+                *self.paused.lock().unwrap() = true;
+                self.wake_all();
+                Response::Ok
+            }
+            Request::Resume => {
+                *self.paused.lock().unwrap() = false;
+                self.resumed.notify_all();
+                Response::Ok
+            }
+            Request::Rediscover => {
+                for state in self.selections.lock().unwrap().values_mut() {
+                    state.rediscover = true;
+                }
+                self.wake_all();
+                Response::Ok
+            }
+            Request::SetLogLevel { level } => {
+                log::level::set(level);
+                Response::Ok
+            }
+            Request::History { request } => {
+                self.history(request).unwrap_or_else(|err| Response::Error {
+                    message: err.to_string(),
+                })
+            }
+        }
+    }
+
+    fn history(&self, request: HistoryRequest) -> MyResult<Response> {
+        let history = self.history.as_ref().ok_or(MyError::HistoryDisabled)?;
+        Ok(match request {
+            HistoryRequest::List => Response::History {
+                entries: history.list()?.iter().map(|e| e.summary()).collect(),
+            },
+            HistoryRequest::Get { id } => Response::HistoryEntry(history.get(id)?),
+            HistoryRequest::Restore { id } => {
+                let entry = history.get(id)?;
+                self.set_content(&entry.group, entry.selection, entry.content)?;
+                Response::Ok
+            }
+            HistoryRequest::Clear => {
+                history.clear()?;
+                Response::Ok
+            }
+        })
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            pid: std::process::id(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            paused: *self.paused.lock().unwrap(),
+            log_level: *log::level::get(),
+            groups: self.by_group(|state| SelectionStatus {
+                clipboards: state.clipboards.len(),
+                syncs: state.syncs,
+                last_sync: state.last_sync.map(|time| time.to_rfc3339()),
+                degraded: state.degraded.clone(),
+            }),
+        }
+    }
+
+    /// Describes the state of every group and selection.
+    fn by_group<T>(
+        &self,
+        describe: impl Fn(&SelectionState) -> T,
+    ) -> BTreeMap<String, BTreeMap<Selection, T>> {
+        let mut groups = BTreeMap::<_, BTreeMap<_, _>>::new();
+        for ((group, selection), state) in self.selections.lock().unwrap().iter() {
+            groups
+                .entry(group.clone())
+                .or_default()
+                .insert(*selection, describe(state));
+        }
+        groups
+    }
+
+    fn wake_all(&self) {
+        for state in self.selections.lock().unwrap().values() {
+            if let Some(wake) = &state.wake {
+                drop(wake.send(String::new()));
+            }
+        }
+    }
+}
+
+/// Connects the sync loop for one selection in one group to the Control.
+pub struct SelectionControl {
+    control: Arc<Control>,
+    group: String,
+    selection: Selection,
+}
+
+impl SelectionControl {
+    /// Records the clipboards that are about to be synced. `wake` is the
+    /// channel that the sync loop waits on for changes. It receives an empty
+    /// display name when the loop needs to check `interrupted`.
+    pub fn discovered(&self, clipboards: &[Box<dyn Clipboard>], wake: Sender<String>) {
+        let displays = clipboards.iter().map(|c| c.display()).collect::<Vec<_>>();
+        let mut old = vec![];
+        self.update(|state| {
+            old = std::mem::take(&mut state.clipboards);
+            *state = SelectionState {
+                clipboards: displays.clone(),
+                wake: Some(wake),
+                alive: Some(Instant::now()),
+                ..Default::default()
+            }
+        });
+        self.publish_changes(&old, &displays);
+    }
+
+    /// Records which displays are synced after some were added or removed, and
+    /// which of them are `degraded`, without resetting anything else.
+    pub fn update_displays(&self, clipboards: &[Box<dyn Clipboard>], degraded: &HashSet<String>) {
+        let displays = clipboards.iter().map(|c| c.display()).collect::<Vec<_>>();
+        let mut old = vec![];
+        self.update(|state| {
+            old = std::mem::replace(&mut state.clipboards, displays.clone());
+            state.degraded = degraded.iter().cloned().collect();
+            state.degraded.sort();
+        });
+        self.publish_changes(&old, &displays);
+    }
+
+    /// Records that `display` stopped responding, which counts towards its
+    /// errors even if it responds again right away.
+    pub fn stopped_responding(&self, display: &str) {
+        let error = (display.to_string(), Instant::now());
+        self.control.errors.lock().unwrap().push(error);
+    }
+
+    /// Records that the sync loop is still running. It's called at least
+    /// every `sync::HEARTBEAT_INTERVAL` while waiting for changes.
+    pub fn alive(&self) {
+        self.update(|state| state.alive = Some(Instant::now()));
+    }
+
+    /// Records what every clipboard contains now.
+    pub fn current(&self, content: &ClipboardContent) {
+        self.update(|state| state.current = Some(content.clone()));
+    }
+
+    /// Records that `content` was copied from `display` to every clipboard.
+    pub fn synced(&self, display: &str, content: &ClipboardContent) {
+        self.update(|state| {
+            state.syncs += 1;
+            state.last_sync = Some(Local::now());
+            state.current = Some(content.clone());
+        });
+        self.control.publish(Event::Synced {
+            group: self.group.clone(),
+            selection: self.selection,
+            display: display.to_string(),
+            content: content.clone(),
+        });
+        if let Some(history) = &self.control.history {
+            if let Err(err) = history.record(&self.group, self.selection, display, content) {
+                log::error!("failed to save clipboard history: {err}");
+            }
+        }
+    }
+
+    /// Whether the sync loop should stop waiting for changes, because the
+    /// clipboards need to be rediscovered, the sync paused, or a history entry
+    /// restored.
+    pub fn interrupted(&self) -> bool {
+        *self.control.paused.lock().unwrap()
+            || self
+                .control
+                .selections
+                .lock()
+                .unwrap()
+                .get(&(self.group.clone(), self.selection))
+                .is_some_and(|state| state.rediscover || state.restore.is_some())
+    }
+
+    /// The contents that should be copied to every clipboard, like a history
+    /// entry, if any were requested.
+    pub fn take_restore(&self) -> Option<ClipboardContent> {
+        let mut restore = None;
+        self.update(|state| restore = state.restore.take());
+        restore
+    }
+
+    pub fn wait_while_paused(&self) {
+        let mut paused = self.control.paused.lock().unwrap();
+        if *paused {
+            let mut old = vec![];
+            self.update(|state| old = std::mem::take(&mut *state).clipboards);
+            self.publish_changes(&old, &[]);
+            log::info!("{} sync is paused in group {}", self.selection, self.group);
+        }
+        while *paused {
+            paused = self.control.resumed.wait(paused).unwrap();
+        }
+    }
+
+    /// Publishes an Event for each display that was added or removed.
+    fn publish_changes(&self, old: &[String], new: &[String]) {
+        for display in old.iter().filter(|d| !new.contains(d)) {
+            self.control.publish(Event::DisplayRemoved {
+                group: self.group.clone(),
+                selection: self.selection,
+                display: display.clone(),
+            });
+        }
+        for display in new.iter().filter(|d| !old.contains(d)) {
+            self.control.publish(Event::DisplayAdded {
+                group: self.group.clone(),
+                selection: self.selection,
+                display: display.clone(),
+            });
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SelectionState)) {
+        let mut selections = self.control.selections.lock().unwrap();
+        f(selections
+            .entry((self.group.clone(), self.selection))
+            .or_default());
+    }
+}
+
+/// $XDG_RUNTIME_DIR/clipboard-sync.sock
+pub fn socket_path() -> MyResult<PathBuf> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .ok_or(MyError::NoRuntimeDir)?;
+
+    Ok(PathBuf::from(dir).join("clipboard-sync.sock"))
+}
+
+/// Starts answering requests on the control socket in a background thread.
+pub fn serve(control: Arc<Control>) -> MyResult<()> {
+    let path = socket_path()?;
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(MyError::ControlSocketInUse(path));
+        }
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    log::debug!("listening for requests on {}", path.display());
+    thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream.map(|stream| answer(&control, stream)) {
+                    Ok(Ok(())) => (),
+                    Ok(Err(err)) => log::warning!("failed to answer control request: {err}"),
+                    Err(err) => log::error!("failed to accept control connection: {err}"),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn answer(control: &Control, stream: UnixStream) -> MyResult<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => {
+            log::debug!("received control request: {request:?}");
+            control.handle(request)
+        }
+        Err(err) => Response::Error {
+            message: format!("invalid request: {err}"),
+        },
+    };
+    writeln!(&stream, "{}", serde_json::to_string(&response)?)?;
+
+    Ok(())
+}
+
+/// Sends a request to the running daemon and waits for its response.
+pub fn send(request: &Request) -> MyResult<Response> {
+    let path = socket_path()?;
+    let stream = UnixStream::connect(&path).map_err(|source| MyError::ControlConnect {
+        path: path.clone(),
+        source,
+    })?;
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    writeln!(&stream, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[test]
+fn test() {
+    let json = |request| serde_json::to_string(&request).unwrap();
+    assert_eq!(r#"{"command":"status"}"#, json(Request::Status));
+    assert_eq!(
+        r#"{"command":"set-log-level","level":"trace"}"#,
+        json(Request::SetLogLevel {
+            level: log::Level::Trace
+        })
+    );
+
+    let request = Request::History {
+        request: HistoryRequest::Get { id: 3 },
+    };
+    assert_eq!(
+        r#"{"command":"history","action":"get","id":3}"#,
+        json(request.clone())
+    );
+    assert_eq!(
+        request,
+        serde_json::from_str(r#"{"command":"history","action":"get","id":3}"#).unwrap()
+    );
+
+    let report = Report::Request(Request::Pause);
+    let json = serde_json::to_string(&report).unwrap();
+    assert_eq!(r#"{"report":"request","command":"pause"}"#, json);
+    let Report::Request(Request::Pause) = serde_json::from_str(&json).unwrap() else {
+        panic!("expected a pause request");
+    };
+
+    let control = Arc::new(Control::new(None));
+    let clipboard = control.selection("default", Selection::Clipboard);
+    assert_eq!(Response::Ok, control.handle(Request::Rediscover));
+    assert!(clipboard.interrupted());
+    let (wake, _woken) = std::sync::mpsc::channel();
+    clipboard.discovered(&[], wake);
+    assert!(!clipboard.interrupted());
+    assert!(control.ready());
+    assert!(control.stalled(Duration::from_secs(60)).is_empty());
+    assert_eq!(vec!["default CLIPBOARD"], control.stalled(Duration::ZERO));
+    control.handle(Request::Pause);
+    assert!(clipboard.interrupted());
+    assert!(control.stalled(Duration::ZERO).is_empty());
+    control.handle(Request::Resume);
+    clipboard.synced(":0", &ClipboardContent::from_text("copied"));
+    let Response::Status(status) = control.handle(Request::Status) else {
+        panic!("expected status");
+    };
+    assert!(!status.paused);
+    assert_eq!(1, status.groups["default"][&Selection::Clipboard].syncs);
+    assert_eq!(
+        r#"{"result":"list","groups":{"default":{"clipboard":[]}}}"#,
+        serde_json::to_string(&control.handle(Request::List)).unwrap()
+    );
+}
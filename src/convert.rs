@@ -0,0 +1,178 @@
+use chrono::Local;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::content::ClipboardContent;
+use crate::log;
+
+/// The image formats that every display is offered whenever an image is
+/// copied, in order of preference as the source of a conversion.
+const IMAGE_FORMATS: [(&str, ImageFormat); 3] = [
+    ("image/png", ImageFormat::Png),
+    ("image/bmp", ImageFormat::Bmp),
+    ("image/jpeg", ImageFormat::Jpeg),
+];
+
+/// Other names that some applications use for the same formats.
+const IMAGE_ALIASES: [(&str, ImageFormat); 3] = [
+    ("image/x-bmp", ImageFormat::Bmp),
+    ("image/x-MS-bmp", ImageFormat::Bmp),
+    ("image/jpg", ImageFormat::Jpeg),
+];
+
+const JPEG_QUALITY: u8 = 90;
+
+/// Converts images on threads of their own, so syncing doesn't wait for them.
+/// The contents are synced as they are right away, and the conversions are
+/// offered once they're done.
+#[derive(Default)]
+pub struct Conversions {
+    done: Arc<Mutex<Vec<Conversion>>>,
+}
+
+struct Conversion {
+    /// the display that the value was copied from
+    origin: Option<String>,
+    original: ClipboardContent,
+    /// the value with the conversions added
+    converted: ClipboardContent,
+}
+
+impl Conversions {
+    /// Starts converting the image in `content`, if it has one that is missing
+    /// some of the common formats. `wake` gets an empty display name once the
+    /// conversion is done.
+    pub fn start(&self, origin: Option<&str>, content: &ClipboardContent, wake: &Sender<String>) {
+        if missing_formats(content).is_empty() || source_format(content).is_none() {
+            return;
+        }
+        let (origin, content) = (origin.map(str::to_string), content.clone());
+        let (done, wake) = (self.done.clone(), wake.clone());
+        let spawned = thread::Builder::new()
+            .name("image conversion".to_string())
+            .spawn(move || {
+                let mut converted = content.clone();
+                add_image_conversions(&mut converted);
+                if converted != content {
+                    done.lock().unwrap().push(Conversion {
+                        origin,
+                        original: content,
+                        converted,
+                    });
+                    drop(wake.send(String::new()));
+                }
+            });
+        if let Err(err) = spawned {
+            log::warning!("not converting the clipboard image: {err}");
+        }
+    }
+
+    /// Whether any conversion is done.
+    pub fn ready(&self) -> bool {
+        !self.done.lock().unwrap().is_empty()
+    }
+
+    /// The conversion of `content`, and the display it was copied from, if it's
+    /// done. Conversions of anything else are outdated, so they're dropped.
+    pub fn take(&self, content: &ClipboardContent) -> Option<(Option<String>, ClipboardContent)> {
+        std::mem::take(&mut *self.done.lock().unwrap())
+            .into_iter()
+            .find(|conversion| conversion.original == *content)
+            .map(|conversion| (conversion.origin, conversion.converted))
+    }
+}
+
+/// If the content includes an image in one of the common formats, converts it
+/// to any of the other common formats that are missing, so it can be pasted
+/// into applications that only accept some of them.
+pub fn add_image_conversions(content: &mut ClipboardContent) {
+    let missing = missing_formats(content);
+    if missing.is_empty() {
+        return;
+    }
+    let Some((source_mime, image)) = source_format(content)
+        .and_then(|(mime, format)| Some((mime, decode(content.get(mime)?, format, mime)?)))
+    else {
+        return;
+    };
+    for (mime, format) in missing {
+        match encode(&image, *format) {
+            Ok(data) => {
+                log::debug!("converted clipboard image from {source_mime} to {mime}");
+                content.insert(*mime, data);
+            }
+            Err(err) => log::warning!("failed to convert clipboard image to {mime}: {err}"),
+        }
+    }
+}
+
+/// The common image formats that the content doesn't have.
+fn missing_formats(content: &ClipboardContent) -> Vec<&'static (&'static str, ImageFormat)> {
+    IMAGE_FORMATS
+        .iter()
+        .filter(|(mime, _)| content.get(mime).is_none())
+        .collect()
+}
+
+/// The format of the image in the content that the others are converted from.
+fn source_format(content: &ClipboardContent) -> Option<(&'static str, ImageFormat)> {
+    IMAGE_FORMATS
+        .iter()
+        .chain(IMAGE_ALIASES.iter())
+        .find(|(mime, _)| content.get(mime).is_some())
+        .copied()
+}
+
+fn decode(data: &[u8], format: ImageFormat, mime: &str) -> Option<DynamicImage> {
+    image::load_from_memory_with_format(data, format)
+        .map_err(|err| log::warning!("failed to decode {mime} from the clipboard: {err}"))
+        .ok()
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> image::ImageResult<Vec<u8>> {
+    let mut data = vec![];
+    match format {
+        // jpeg has no alpha channel
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut data, ImageOutputFormat::Jpeg(JPEG_QUALITY))?,
+        format => image.write_to(&mut data, format)?,
+    }
+    Ok(data)
+}
+
+#[test]
+fn test() {
+    let png = encode(
+        &DynamicImage::ImageRgba8(image::RgbaImage::new(3, 2)),
+        ImageFormat::Png,
+    )
+    .unwrap();
+    let mut content = ClipboardContent::from_text("not an image");
+    add_image_conversions(&mut content);
+    assert_eq!(ClipboardContent::from_text("not an image"), content);
+
+    content.insert("image/png", png.clone());
+    add_image_conversions(&mut content);
+    assert_eq!(Some(png.as_slice()), content.get("image/png"));
+    for (mime, format) in IMAGE_FORMATS {
+        let image = image::load_from_memory_with_format(content.get(mime).unwrap(), format);
+        assert_eq!((3, 2), image::GenericImageView::dimensions(&image.unwrap()));
+    }
+
+    // conversions in the background are only offered for the contents they
+    // were started for
+    let (wake, woken) = std::sync::mpsc::channel();
+    let conversions = Conversions::default();
+    let mut copied = ClipboardContent::new();
+    copied.insert("image/png", png);
+    conversions.start(Some(":1"), &copied, &wake);
+    conversions.start(None, &ClipboardContent::from_text("text"), &wake);
+    assert_eq!("", woken.recv().unwrap());
+    assert!(conversions.ready());
+    let (origin, converted) = conversions.take(&copied).unwrap();
+    assert_eq!(Some(":1".to_string()), origin);
+    assert!(converted.get("image/jpeg").is_some());
+    assert!(!conversions.ready());
+}
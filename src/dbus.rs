@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::Local;
+use zbus::blocking::connection::Builder;
+use zbus::blocking::Connection;
+use zbus::fdo;
+use zbus::SignalContext;
+
+use crate::clipboard::{backend, Selection};
+use crate::config::Config;
+use crate::content::ClipboardContent;
+use crate::control::{Control, Event, HistoryRequest, Request, Response};
+use crate::error::MyResult;
+use crate::log;
+
+pub const NAME: &str = "org.clipboardsync.Daemon";
+pub const PATH: &str = "/org/clipboardsync/Daemon";
+
+/// Clipboard contents as sent over D-Bus, keyed by mime type.
+type Content = HashMap<String, Vec<u8>>;
+/// (group, selection, display, backend, health)
+type DisplayInfo = (String, String, String, String, String);
+/// (id, time, group, selection, display, mime types, size)
+type HistorySummary = (u64, String, String, String, String, Vec<String>, u64);
+
+/// Publishes the daemon on the session bus as org.clipboardsync.Daemon.
+pub fn serve(control: Arc<Control>, config: &Config) -> MyResult<()> {
+    let _service = serve_on(Builder::session()?, control, config.dbus_signal_content)?;
+    Ok(())
+}
+
+/// Publishes the daemon on the bus that `builder` connects to, and emits a
+/// signal for each Event until the daemon exits.
+fn serve_on(builder: Builder, control: Arc<Control>, signal_content: bool) -> MyResult<Connection> {
+    let events = control.subscribe();
+    let connection = builder
+        .name(NAME)?
+        .serve_at(PATH, Daemon { control })?
+        .build()?;
+    // the thread's clone keeps the service running after `connection` is dropped
+    let emitter = connection.clone();
+    std::thread::spawn(move || {
+        let ctxt = SignalContext::new(emitter.inner(), PATH).unwrap();
+        for event in events {
+            let result = match event {
+                Event::Synced {
+                    group,
+                    selection,
+                    display,
+                    content,
+                } => {
+                    let content = match signal_content && !content.is_secret() {
+                        true => to_dbus(content),
+                        false => Content::new(),
+                    };
+                    let selection = selection_name(selection);
+                    let signal =
+                        Daemon::clipboard_changed(&ctxt, &group, selection, &display, content);
+                    zbus::block_on(signal)
+                }
+                Event::DisplayAdded {
+                    group,
+                    selection,
+                    display,
+                } => zbus::block_on(Daemon::display_added(
+                    &ctxt,
+                    &group,
+                    selection_name(selection),
+                    &display,
+                )),
+                Event::DisplayRemoved {
+                    group,
+                    selection,
+                    display,
+                } => zbus::block_on(Daemon::display_removed(
+                    &ctxt,
+                    &group,
+                    selection_name(selection),
+                    &display,
+                )),
+            };
+            if let Err(err) = result {
+                log::warning!("failed to emit a d-bus signal: {err}");
+            }
+        }
+    });
+
+    Ok(connection)
+}
+
+struct Daemon {
+    control: Arc<Control>,
+}
+
+#[zbus::interface(name = "org.clipboardsync.Daemon")]
+impl Daemon {
+    /// The contents of a selection, keyed by mime type. Anything on the
+    /// session bus can ask, so secrets are refused, like in the signals.
+    fn get_content(&self, group: &str, selection: &str) -> fdo::Result<Content> {
+        let selection = parse_selection(selection)?;
+        let content = self.control.content(group, selection).unwrap_or_default();
+        if content.is_secret() {
+            return Err(fdo::Error::AccessDenied(
+                "the contents are secret".to_string(),
+            ));
+        }
+        Ok(to_dbus(content))
+    }
+
+    /// Copies the contents to every clipboard of a selection.
+    fn set_content(&self, group: &str, selection: &str, content: Content) -> fdo::Result<()> {
+        let mut copied = ClipboardContent::new();
+        for (mime_type, data) in content {
+            copied.insert(mime_type, data);
+        }
+        self.control
+            .set_content(group, parse_selection(selection)?, copied)
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    /// Every display that is being synced, with its backend and health.
+    fn list_displays(&self) -> fdo::Result<Vec<DisplayInfo>> {
+        let Response::List { groups } = self.control.handle(Request::List) else {
+            return Err(fdo::Error::Failed(
+                "failed to list the displays".to_string(),
+            ));
+        };
+        let status = match self.control.handle(Request::Status) {
+            Response::Status(status) => status.groups,
+            _ => Default::default(),
+        };
+        let mut displays = vec![];
+        for (group, selections) in groups {
+            for (selection, names) in selections {
+                let degraded = status
+                    .get(&group)
+                    .and_then(|s| s.get(&selection))
+                    .map(|s| s.degraded.clone())
+                    .unwrap_or_default();
+                for name in names {
+                    let health = match degraded.contains(&name) {
+                        true => "degraded",
+                        false => "ok",
+                    };
+                    displays.push((
+                        group.clone(),
+                        selection_name(selection).to_string(),
+                        name.clone(),
+                        backend(&name).to_string(),
+                        health.to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(displays)
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        check(self.control.handle(Request::Pause))
+    }
+
+    fn resume(&self) -> fdo::Result<()> {
+        check(self.control.handle(Request::Resume))
+    }
+
+    /// Describes every entry in the history, from oldest to newest.
+    fn history(&self) -> fdo::Result<Vec<HistorySummary>> {
+        let request = Request::History {
+            request: HistoryRequest::List,
+        };
+        match self.control.handle(request) {
+            Response::History { entries } => Ok(entries
+                .into_iter()
+                .map(|e| {
+                    let selection = selection_name(e.selection).to_string();
+                    let time = e.time.to_rfc3339();
+                    let size = e.size as u64;
+                    (
+                        e.id,
+                        time,
+                        e.group,
+                        selection,
+                        e.display,
+                        e.mime_types,
+                        size,
+                    )
+                })
+                .collect()),
+            response => Err(error(response)),
+        }
+    }
+
+    /// The contents of a history entry, keyed by mime type.
+    fn history_entry(&self, id: u64) -> fdo::Result<Content> {
+        let request = Request::History {
+            request: HistoryRequest::Get { id },
+        };
+        match self.control.handle(request) {
+            Response::HistoryEntry(entry) => Ok(to_dbus(entry.content)),
+            response => Err(error(response)),
+        }
+    }
+
+    /// A change was copied from the `origin` display to the others. The
+    /// contents are only included when the daemon is configured to send them,
+    /// and never for secrets.
+    #[zbus(signal)]
+    async fn clipboard_changed(
+        ctxt: &SignalContext<'_>,
+        group: &str,
+        selection: &str,
+        origin: &str,
+        content: Content,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn display_added(
+        ctxt: &SignalContext<'_>,
+        group: &str,
+        selection: &str,
+        display: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn display_removed(
+        ctxt: &SignalContext<'_>,
+        group: &str,
+        selection: &str,
+        display: &str,
+    ) -> zbus::Result<()>;
+}
+
+fn check(response: Response) -> fdo::Result<()> {
+    match response {
+        Response::Ok => Ok(()),
+        response => Err(error(response)),
+    }
+}
+
+fn error(response: Response) -> fdo::Error {
+    match response {
+        Response::Error { message } => fdo::Error::Failed(message),
+        response => fdo::Error::Failed(format!("unexpected response: {response:?}")),
+    }
+}
+
+fn to_dbus(content: ClipboardContent) -> Content {
+    content
+        .iter()
+        .map(|(mime_type, data)| (mime_type.to_string(), data.to_vec()))
+        .collect()
+}
+
+fn selection_name(selection: Selection) -> &'static str {
+    match selection {
+        Selection::Clipboard => "clipboard",
+        Selection::Primary => "primary",
+    }
+}
+
+fn parse_selection(name: &str) -> fdo::Result<Selection> {
+    match name {
+        "clipboard" => Ok(Selection::Clipboard),
+        "primary" => Ok(Selection::Primary),
+        _ => Err(fdo::Error::InvalidArgs(format!(
+            "unknown selection: {name}"
+        ))),
+    }
+}
+
+#[test]
+fn test() {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+
+    use crate::clipboard::Clipboard;
+
+    #[derive(Debug)]
+    struct Fake(&'static str);
+    impl Clipboard for Fake {
+        fn display(&self) -> String {
+            self.0.to_string()
+        }
+        fn get(&self) -> MyResult<ClipboardContent> {
+            Ok(ClipboardContent::new())
+        }
+        fn set(&self, _: &ClipboardContent) -> MyResult<()> {
+            Ok(())
+        }
+    }
+
+    // a private bus, so the test doesn't depend on or disturb the session
+    let daemon = Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address=1"])
+        .stdout(Stdio::piped())
+        .spawn();
+    let Ok(mut daemon) = daemon else {
+        crate::mock::skip("the d-bus test", "dbus-daemon");
+        return;
+    };
+    let mut address = String::new();
+    BufReader::new(daemon.stdout.take().unwrap())
+        .read_line(&mut address)
+        .unwrap();
+
+    let control = Arc::new(Control::new(None));
+    let selection = control.selection("default", Selection::Clipboard);
+    let builder = Builder::address(address.trim()).unwrap();
+    let _service = serve_on(builder, control.clone(), true).unwrap();
+    let client = Builder::address(address.trim()).unwrap().build().unwrap();
+    let proxy = zbus::blocking::Proxy::new(&client, NAME, PATH, NAME).unwrap();
+    let mut added = proxy.receive_signal("DisplayAdded").unwrap();
+    let mut changed = proxy.receive_signal("ClipboardChanged").unwrap();
+
+    let (wake, woken) = std::sync::mpsc::channel();
+    let clipboards: Vec<Box<dyn Clipboard>> = vec![Box::new(Fake("wayland-1"))];
+    selection.discovered(&clipboards, wake);
+    let (group, _, display): (String, String, String) =
+        added.next().unwrap().body().deserialize().unwrap();
+    assert_eq!(("default", "wayland-1"), (&*group, &*display));
+    let displays: Vec<DisplayInfo> = proxy.call("ListDisplays", &()).unwrap();
+    assert_eq!(
+        vec![(
+            "default".to_string(),
+            "clipboard".to_string(),
+            "wayland-1".to_string(),
+            "wayland".to_string(),
+            "ok".to_string()
+        )],
+        displays
+    );
+
+    proxy.call::<_, _, ()>("Pause", &()).unwrap();
+    assert!(selection.interrupted());
+    let content = Content::from([("text/plain".to_string(), b"set".to_vec())]);
+    let paused = proxy.call::<_, _, ()>("SetContent", &("default", "clipboard", &content));
+    assert!(paused.is_err());
+    proxy.call::<_, _, ()>("Resume", &()).unwrap();
+    proxy
+        .call::<_, _, ()>("SetContent", &("default", "clipboard", &content))
+        .unwrap();
+    woken.recv().unwrap();
+    let restored = selection.take_restore().unwrap();
+    assert_eq!(Some(&b"set"[..]), restored.get("text/plain"));
+
+    selection.synced("wayland-1", &restored);
+    let (_, _, origin, sent): (String, String, String, Content) =
+        changed.next().unwrap().body().deserialize().unwrap();
+    assert_eq!(("wayland-1", &content), (&*origin, &sent));
+    let got: Content = proxy.call("GetContent", &("default", "clipboard")).unwrap();
+    assert_eq!(content, got);
+    let mut secret = restored.clone();
+    secret.insert("x-kde-passwordManagerHint", b"secret".to_vec());
+    selection.synced("wayland-1", &secret);
+    let got = proxy.call::<_, _, Content>("GetContent", &("default", "clipboard"));
+    assert!(got.is_err(), "secrets are not sent");
+    let history = proxy.call::<_, _, Vec<HistorySummary>>("History", &());
+    assert!(history.is_err(), "history is disabled");
+
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+}
@@ -0,0 +1,382 @@
+use chrono::Local;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::{fork, getppid, pipe, Pid};
+use nix::{sys::wait::waitpid, unistd::ForkResult};
+use std::convert::Infallible;
+use std::f64::consts::E;
+use std::fs::File;
+use std::os::unix::io::FromRawFd;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::SystemTime;
+use std::{thread::sleep, time::Duration};
+
+use crate::clipboard::Selection;
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+use crate::control::{Control, Event, SelectionControl};
+use crate::dbus;
+use crate::error::{MyError, MyResult};
+use crate::health;
+use crate::history::History;
+use crate::log;
+use crate::peer::Peers;
+use crate::sync;
+use crate::systemd;
+
+/// Syncs the clipboards of every display, in each group, for as long as it
+/// runs. It's created with a [`SyncEngineBuilder`].
+pub struct SyncEngine {
+    config: Config,
+    control: Arc<Control>,
+    control_socket: bool,
+}
+
+/// Configures a [`SyncEngine`].
+pub struct SyncEngineBuilder {
+    config: Config,
+    control_socket: bool,
+    callbacks: Vec<Callback>,
+}
+
+/// Called with each Event.
+type Callback = Box<dyn Fn(&Event) + Send>;
+
+impl SyncEngineBuilder {
+    /// Uses these settings instead of the defaults.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Whether to listen on the control socket, which the subcommands talk
+    /// to. Enabled by default.
+    pub fn control_socket(mut self, enabled: bool) -> Self {
+        self.control_socket = enabled;
+        self
+    }
+
+    /// Calls `callback` with every change that is synced, and every display
+    /// that is added or removed. The callbacks are called in order, on a
+    /// thread of their own. When running forked, they're called in this
+    /// process, with the events that the child sends back.
+    pub fn on_event(mut self, callback: impl Fn(&Event) + Send + 'static) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    pub fn build(self) -> SyncEngine {
+        let control = Arc::new(Control::new(History::new(&self.config)));
+        if !self.callbacks.is_empty() {
+            let events = control.subscribe();
+            let callbacks = self.callbacks;
+            thread::Builder::new()
+                .name("sync events".to_string())
+                .spawn(move || {
+                    for event in events {
+                        callbacks.iter().for_each(|callback| callback(&event));
+                    }
+                })
+                .expect("failed to spawn event thread");
+        }
+        SyncEngine {
+            config: self.config,
+            control,
+            control_socket: self.control_socket,
+        }
+    }
+}
+
+impl SyncEngine {
+    pub fn builder() -> SyncEngineBuilder {
+        SyncEngineBuilder {
+            config: Config::default(),
+            control_socket: true,
+            callbacks: vec![],
+        }
+    }
+
+    /// Inspects and changes the running sync, like the control socket does.
+    pub fn control(&self) -> &Arc<Control> {
+        &self.control
+    }
+
+    /// Syncs in a child process, which is restarted whenever it exits, and
+    /// whenever its health crosses one of the thresholds in the config, so its
+    /// state is cleaned up. Never returns.
+    pub fn run_forked(&self) -> ! {
+        let (config, control) = (&self.config, &self.control);
+        log::info!("started clipboard sync manager");
+        let mut panics = 0;
+        loop {
+            let (reader, writer) = pipe().expect("Failed to create pipe");
+            let (reader, writer) =
+                unsafe { (File::from_raw_fd(reader), File::from_raw_fd(writer)) };
+            match unsafe { fork() }.expect("Failed to fork") {
+                ForkResult::Parent { child } => {
+                    drop(writer);
+                    let following = control.follow_child(reader);
+                    log::debug!("child process {child} successfully initialized.");
+                    let monitor = health::monitor(child, config, control.clone());
+                    let status = waitpid(Some(child), None)
+                        .expect("there was a problem managing the child process, so the service is exiting. check that pid {child} is not running before restarting this service");
+                    drop(monitor);
+                    log::debug!("child process {child} completed with: {status:?}");
+                    drop(following.join());
+                    if let WaitStatus::Exited(_, 101) = status {
+                        panics += 1;
+                        if panics < 4 {
+                            log::fatal!("child process {child} panicked. giving it another try");
+                        } else {
+                            panic!("child process {child} panicked too many times.");
+                        }
+                    }
+                    sleep(Duration::from_secs(1));
+                }
+                ForkResult::Child => {
+                    drop(reader);
+                    // the child is never left running without its parent
+                    unsafe { nix::libc::prctl(nix::libc::PR_SET_PDEATHSIG, nix::libc::SIGKILL) };
+                    if getppid() == Pid::from_raw(1) {
+                        std::process::exit(0);
+                    }
+                    control.report_to_parent(writer);
+                    health::report(control.clone());
+                    self.run().unwrap();
+                    std::process::exit(0);
+                }
+            }
+        }
+    }
+
+    /// Syncs until one of the selections fails too often, and returns its
+    /// error, or NoGroups if there is nothing to sync. Panics in the sync
+    /// threads are resumed here.
+    pub fn run(&self) -> MyResult<()> {
+        let (config, control) = (&self.config, &self.control);
+        log::info!("starting clipboard sync");
+        if self.control_socket {
+            if let Err(err) = crate::control::serve(control.clone()) {
+                log::warning!("the control socket is unavailable: {err}");
+            }
+        }
+        if config.dbus {
+            if let Err(err) = dbus::serve(control.clone(), config) {
+                log::warning!("the d-bus service is unavailable: {err}");
+            }
+        }
+        let peers = Peers::start(config).unwrap_or_else(|err| {
+            log::error!("not syncing with peers: {err}");
+            None
+        });
+        let mut selections = vec![Selection::Clipboard];
+        if config.primary {
+            selections.push(Selection::Primary);
+        }
+        // each selection in each group is synced in its own thread. if any of them
+        // stops, this returns so the whole process can be restarted.
+        let (stopped_tx, stopped_rx) = mpsc::channel();
+        for group in config.group_names() {
+            for &selection in &selections {
+                let stopped = stopped_tx.clone();
+                let config = config.clone();
+                let control = control.selection(&group, selection);
+                let group = group.clone();
+                let peers = peers.clone();
+                thread::Builder::new()
+                    .name(format!("sync {group} {selection}"))
+                    .spawn(move || {
+                        let result = std::panic::catch_unwind(|| {
+                            run_selection(selection, &group, &config, &control, peers.as_ref())
+                        });
+                        drop(stopped.send((group, selection, result)));
+                    })
+                    .expect("failed to spawn sync thread");
+            }
+        }
+        drop(stopped_tx);
+        systemd::start(control.clone());
+        let Ok((group, selection, result)) = stopped_rx.recv() else {
+            return Err(MyError::NoGroups);
+        };
+        match result {
+            Ok(Ok(never)) => match never {},
+            Ok(Err(err)) => {
+                log::error!("{selection} sync stopped in group {group}: {err}");
+                Err(err)
+            }
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// Syncs one selection in one group. Each time the sync is interrupted by the control
+/// socket, it waits for any pause to end, then rediscovers the clipboards. It only
+/// returns when it fails too often.
+fn run_selection(
+    selection: Selection,
+    group: &str,
+    config: &Config,
+    control: &SelectionControl,
+    peers: Option<&Arc<Peers>>,
+) -> MyResult<Infallible> {
+    loop {
+        control.wait_while_paused();
+        loop_with_error_pain_management(
+            sync::get_clipboards(selection, group, config, peers)?,
+            |clipboards| {
+                sync::keep_synced(clipboards, selection, group, config, control, &SystemClock)
+            },
+            |clipboards| {
+                *clipboards = sync::get_clipboards(selection, group, config, peers)?;
+                Ok(())
+            },
+            config.pain_threshold,
+            &SystemClock,
+        )?;
+    }
+}
+
+/// Execute an action with a sophisticated retry mechanism
+/// If the action fails:
+/// - 1. run a recovery step to manipulate the input
+/// - 2. attempt to execute the action again
+///
+/// If the action or the recovery fails too frequently, exit
+fn loop_with_error_pain_management<
+    Input,
+    Return,
+    Action: Fn(&mut Input) -> MyResult<Return>,
+    Recovery: Fn(&mut Input) -> MyResult<()>,
+>(
+    // data passed into action and reset by recovery
+    initial_input: Input,
+    // action to attempt on every iteration
+    action: Action,
+    // action to attempt on every error. if it fails, the input is kept as it was
+    // and the error counts like any other
+    recovery: Recovery,
+    // exit once the total pain of recent errors exceeds this
+    pain_threshold: f64,
+    // tells the time of each error, and waits before retrying
+    clock: &impl Clock,
+) -> MyResult<Return> {
+    let mut input = initial_input;
+    let mut error_times = vec![];
+    let mut errors = vec![];
+    loop {
+        match action(&mut input) {
+            Ok(ret) => return Ok(ret),
+            Err(err) => {
+                log::fatal!(event = "failed", error = err; "action exited with error: {:?}", err);
+                let now = clock.now();
+                error_times.push(now);
+                errors.push(err);
+                if total_pain(now, error_times.clone()) > pain_threshold {
+                    return Err(MyError::Crash {
+                        msg: "too many errors, exiting".to_string(),
+                        cause: errors,
+                    });
+                }
+                if let Err(err) = recovery(&mut input) {
+                    log::error!(event = "failed", error = err; "recovery failed: {err}");
+                    error_times.push(clock.now());
+                    errors.push(err);
+                }
+                clock.sleep(Duration::from_millis(1000));
+            }
+        }
+        log::info!("retrying");
+    }
+}
+
+/// Sum the pain of numerous painful events, measured by how long ago they
+/// happened.
+fn total_pain(now: SystemTime, errors: Vec<SystemTime>) -> f64 {
+    errors
+        .into_iter()
+        .map(|et| remaining_pain(now.duration_since(et).unwrap().as_secs()))
+        .sum()
+}
+
+/// Looks at a painful event and determines how much of its pain is left.  
+/// calculated as exponential decay with a half-life of 1 minute.
+fn remaining_pain(seconds_ago: u64) -> f64 {
+    E.powf(-(seconds_ago as f64) / 86.561_702_453_337_8)
+}
+
+#[test]
+fn test() {
+    use crate::mock::MockClock;
+    use std::cell::Cell;
+
+    assert!((remaining_pain(60) - 0.5).abs() < 0.001);
+
+    // three errors within a few seconds are too many
+    let clock = MockClock::new();
+    let attempts = Cell::new(0);
+    let fail = |_: &mut u32| -> MyResult<u32> {
+        attempts.set(attempts.get() + 1);
+        Err(MyError::NoClipboards)
+    };
+    let recover = |n: &mut u32| {
+        *n += 1;
+        Ok(())
+    };
+    let result = loop_with_error_pain_management(0, fail, recover, 2.5, &clock);
+    assert!(matches!(result, Err(MyError::Crash { .. })));
+    assert_eq!(3, attempts.get());
+
+    // a recovery that fails counts as another error
+    attempts.set(0);
+    let broken = |_: &mut u32| Err(MyError::NoClipboards);
+    let result = loop_with_error_pain_management(0, fail, broken, 2.5, &clock);
+    assert!(matches!(result, Err(MyError::Crash { .. })));
+    assert_eq!(2, attempts.get());
+
+    // errors that are far enough apart have decayed by the time the next one
+    // happens, so recovery can be attempted indefinitely
+    let fail_slowly = |n: &mut u32| {
+        if *n == 10 {
+            return Ok(*n);
+        }
+        clock.advance(Duration::from_secs(120));
+        Err(MyError::NoClipboards)
+    };
+    let result = loop_with_error_pain_management(0, fail_slowly, recover, 2.5, &clock);
+    assert_eq!(10, result.unwrap());
+}
+
+#[test]
+fn forked() {
+    use crate::config::GroupConfig;
+
+    // a terminal is the only display that needs nothing else running
+    let pty = nix::pty::openpty(None, None).unwrap();
+    let tty = nix::unistd::ttyname(pty.slave).unwrap();
+    let display = format!("osc52:{}", tty.display());
+    let config = Config {
+        terminals: vec![tty],
+        groups: [(
+            "test".to_string(),
+            GroupConfig {
+                displays: vec![display.clone()],
+            },
+        )]
+        .into(),
+        ignore_ungrouped: true,
+        dbus: false,
+        ..Config::default()
+    };
+
+    // callbacks are called in the parent with the events of the child
+    let (events_tx, events) = mpsc::channel();
+    let engine = SyncEngine::builder()
+        .config(config)
+        .control_socket(false)
+        .on_event(move |event| drop(events_tx.send(event.clone())))
+        .build();
+    thread::spawn(move || engine.run_forked());
+    let event = events.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert!(matches!(event, Event::DisplayAdded { display: d, .. } if d == display));
+}
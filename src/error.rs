@@ -1,6 +1,11 @@
 use std::cell::{BorrowError, BorrowMutError};
 use std::error::Error as StdError;
 use std::fmt::{Debug, Display};
+use std::path::PathBuf;
+use std::sync::PoisonError;
+use std::time::Duration;
+
+use crate::clipboard::Selection;
 
 pub type MyResult<T> = Result<T, MyError>;
 
@@ -9,31 +14,129 @@ pub enum MyError {
     /// Only use this catchall to deal with unexpected errors that are very
     /// difficult to deal with in another way.
     #[error("Unknown error: {0}")]
-    Generic(#[from] Box<dyn StdError>),
+    Generic(#[from] Box<dyn StdError + Send>),
 
     /// High level error to represent the idea that the application is crashing,
     /// indicating the cause(s) of that crash.
     #[error("Application crash '{msg}': {cause:#?}")]
     Crash { msg: String, cause: Vec<MyError> },
 
-    #[error("failed to get wlr clipboard: {0}")]
-    WlcrsPaste(#[from] wl_clipboard_rs::paste::Error),
+    #[error("failed to connect to wayland display: {0}")]
+    WaylandConnect(#[from] wayland_client::ConnectError),
+
+    #[error("wayland display does not support version {version} of {name}")]
+    MissingWaylandProtocol { name: &'static str, version: u32 },
+
+    #[error("wayland display has no seats")]
+    NoSeats,
+
+    #[error("failed to connect to x11 display: {0}")]
+    X11Connect(#[from] xcb::ConnError),
+
+    #[error("x11 request failed: {0}")]
+    X11Reply(#[from] xcb::ReplyError),
+
+    #[error("x11 request failed: {0}")]
+    X11Request(#[from] xcb::GenericError),
+
+    #[error("x11 display does not support the {0} extension")]
+    MissingX11Extension(&'static str),
+
+    #[error("timed out waiting for the x11 selection owner")]
+    X11Timeout,
+
+    #[error("failed to take ownership of the x11 selection")]
+    X11NotOwner,
+
+    #[error("{0}")]
+    Nix(#[from] nix::Error),
+
+    #[error("a thread panicked while holding a lock")]
+    LockPoisoned,
+
+    #[error("clipboard watcher thread exited before it was ready")]
+    WatcherDied,
+
+    #[error("{display} did not respond within {timeout:?}")]
+    Timeout { display: String, timeout: Duration },
+
+    #[error("{0} has not finished a call that timed out")]
+    Unresponsive(String),
 
-    #[error("failed to set wlr clipboard: {0}")]
-    WlcrsCopy(#[from] wl_clipboard_rs::copy::Error),
+    #[error("a call to {0} panicked")]
+    CallPanicked(String),
 
     #[error("{0}")]
     Io(#[from] std::io::Error),
 
+    #[error("failed to read config file {path:?}: {source}")]
+    ConfigRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("invalid config file {path:?}: {source}")]
+    ConfigParse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("XDG_RUNTIME_DIR is not set, so there is nowhere to put the control socket")]
+    NoRuntimeDir,
+
+    #[error("the control socket {0:?} is already in use by another instance")]
+    ControlSocketInUse(PathBuf),
+
+    #[error(
+        "failed to connect to the control socket {path:?}, is clipboard-sync running? {source}"
+    )]
+    ControlConnect {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[error("{0}")]
-    TerminalClipboard(#[from] StandardizedError<terminal_clipboard::ClipboardError>),
+    Json(#[from] serde_json::Error),
+
+    #[error("invalid peer key file {path:?}: {reason}")]
+    PeerKey { path: PathBuf, reason: String },
+
+    #[error("peer handshake failed: {0}")]
+    PeerHandshake(String),
+
+    #[error("a message from a peer could not be decrypted, is it using the same key?")]
+    PeerAuth,
+
+    #[error("a peer sent a message of {0} bytes, which is too big")]
+    PeerFrameTooBig(usize),
+
+    #[error("{0:?} is not a terminal")]
+    NotATerminal(PathBuf),
+
+    #[error("terminal {0:?} sent an invalid OSC 52 response")]
+    Osc52Response(PathBuf),
+
+    #[error("sync is paused")]
+    Paused,
+
+    #[error("{1} is not being synced in group {0}")]
+    NotSynced(String, Selection),
 
     #[error("{0}")]
-    Arboard(#[from] arboard::Error),
+    DBus(#[from] zbus::Error),
+
+    #[error("clipboard history is disabled")]
+    HistoryDisabled,
+
+    #[error("there is no clipboard history entry {0}")]
+    NoHistoryEntry(u64),
 
     #[error("No clipboards.")]
     NoClipboards,
 
+    #[error("no groups are configured, so there is nothing to sync")]
+    NoGroups,
+
     #[error("{0}")]
     BorrowError(#[from] BorrowError),
 
@@ -41,6 +144,22 @@ pub enum MyError {
     BorrowMutError(#[from] BorrowMutError),
 }
 
+impl MyError {
+    /// Whether a display didn't respond in time, in which case it may still
+    /// recover later.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, MyError::Timeout { .. } | MyError::Unresponsive(_))
+    }
+}
+
+impl<T> From<PoisonError<T>> for MyError {
+    fn from(_: PoisonError<T>) -> Self {
+        MyError::LockPoisoned
+    }
+}
+
+/// The fields are only read through the Debug implementation.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct StandardizedError<E: Debug> {
     pub inner: E,
@@ -55,6 +174,7 @@ impl<E: Debug> Display for StandardizedError<E> {
 
 impl<E: Debug> StdError for StandardizedError<E> {}
 
+#[allow(unused)]
 pub trait Standardize<T, E: Sized + Debug>: Sized {
     /// Convert any Sized + Debug into a std::error::Error
     fn standardize(self) -> Result<T, StandardizedError<E>>;
@@ -84,12 +204,13 @@ impl<T, E: Sized + Debug> Standardize<T, E> for (Result<T, E>, StdIo) {
     }
 }
 
-pub trait Generify<T, E: 'static + StdError> {
+#[allow(unused)]
+pub trait Generify<T, E: 'static + StdError + Send> {
     /// Convert any std::error::Error into MyError::Generic
     fn generify(self) -> Result<T, MyError>;
 }
 
-impl<T, E: 'static + StdError> Generify<T, E> for Result<T, E> {
+impl<T, E: 'static + StdError + Send> Generify<T, E> for Result<T, E> {
     fn generify(self) -> Result<T, MyError> {
         match self {
             Ok(ok) => Ok(ok),
@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::{Pid, SysconfVar};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::control::Control;
+use crate::log;
+
+/// How often the child reports its health to the parent.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the parent checks whether the child needs to be restarted.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The errors of each display are counted over this long.
+pub const ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+/// What a child process knows about its own health, which it reports to the
+/// parent.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Health {
+    /// how many seconds ago each sync loop showed that it's still running,
+    /// keyed by "group selection", or None if it hasn't discovered its
+    /// clipboards yet
+    pub heartbeats: BTreeMap<String, Option<f64>>,
+    /// how many times each display stopped responding in the last minute
+    pub errors: BTreeMap<String, usize>,
+}
+
+/// Reports the health of this child process to the parent for as long as it
+/// runs.
+pub fn report(control: Arc<Control>) {
+    thread::Builder::new()
+        .name("health".to_string())
+        .spawn(move || loop {
+            control.report_health(Health {
+                heartbeats: control
+                    .heartbeats()
+                    .into_iter()
+                    .map(|(name, age)| (name, age.map(|age| age.as_secs_f64())))
+                    .collect(),
+                errors: control.display_errors(ERROR_WINDOW),
+            });
+            thread::sleep(REPORT_INTERVAL);
+        })
+        .expect("failed to spawn health thread");
+}
+
+/// Stops monitoring the child when it's dropped.
+pub struct Monitor(#[allow(unused)] Sender<()>);
+
+/// Restarts the child when it's unhealthy, according to the thresholds in the
+/// config, and logs why.
+pub fn monitor(child: Pid, config: &Config, control: Arc<Control>) -> Monitor {
+    let (stop, stopped) = mpsc::channel();
+    let config = config.clone();
+    let started = Instant::now();
+    thread::spawn(move || loop {
+        match stopped.recv_timeout(CHECK_INTERVAL) {
+            Err(RecvTimeoutError::Timeout) => (),
+            _ => return,
+        }
+        let observation = Observation {
+            age: started.elapsed(),
+            // reports from a previous child don't count
+            report: control
+                .child_health()
+                .filter(|(received, _)| *received >= started)
+                .map(|(received, health)| (received.elapsed(), health)),
+            paused: control.paused(),
+            open_files: open_files(child),
+            memory: memory(child),
+        };
+        let problems = problems(&config, &observation);
+        if problems.is_empty() {
+            continue;
+        }
+        let reason = problems.join(", ");
+        log::warning!(
+            event = "restart", reason = reason;
+            "restarting child process {child}: {reason}"
+        );
+        if let Err(e) = signal::kill(child, Signal::SIGTERM) {
+            log::error!("error killing child process {child}: {e}")
+        }
+        return;
+    });
+
+    Monitor(stop)
+}
+
+/// What the parent knows about the child.
+#[derive(Debug, Default)]
+struct Observation {
+    /// how long the child has been running
+    age: Duration,
+    /// how long ago the child last reported its health, and what it reported
+    report: Option<(Duration, Health)>,
+    paused: bool,
+    open_files: Option<usize>,
+    /// resident memory, in bytes
+    memory: Option<u64>,
+}
+
+/// Every threshold in the config that the child crossed. Each one that is 0
+/// is ignored.
+fn problems(config: &Config, observation: &Observation) -> Vec<String> {
+    let mut problems = vec![];
+    let restart_interval = Duration::from_secs(config.restart_interval_secs);
+    if config.restart_interval_secs > 0 && observation.age >= restart_interval {
+        problems.push(format!("it has run for {restart_interval:?}"));
+    }
+    let max_heartbeat_age = Duration::from_secs(config.max_heartbeat_age_secs);
+    // sync loops have a chance to discover their clipboards first, and they
+    // don't run at all while paused
+    if config.max_heartbeat_age_secs > 0
+        && observation.age >= max_heartbeat_age
+        && !observation.paused
+    {
+        match &observation.report {
+            Some((age, _)) if *age > max_heartbeat_age => {
+                problems.push(format!("it has not reported its health for {age:?}"))
+            }
+            None => problems.push(format!(
+                "it has not reported its health since it started {:?} ago",
+                observation.age
+            )),
+            Some((_, health)) => {
+                for (name, age) in &health.heartbeats {
+                    match age {
+                        Some(age) if *age <= max_heartbeat_age.as_secs_f64() => (),
+                        Some(age) => problems.push(format!("{name} has not synced for {age:.0}s")),
+                        None => problems.push(format!("{name} has not started syncing")),
+                    }
+                }
+            }
+        }
+    }
+    if let (Some((_, health)), max @ 1..) =
+        (&observation.report, config.max_display_errors_per_minute)
+    {
+        for (display, &errors) in &health.errors {
+            if errors >= max {
+                problems.push(format!(
+                    "{display} stopped responding {errors} times in a minute"
+                ));
+            }
+        }
+    }
+    if let (Some(files), max @ 1..) = (observation.open_files, config.max_open_files) {
+        if files >= max {
+            problems.push(format!("it has {files} open files"));
+        }
+    }
+    if let (Some(memory), max @ 1..) = (observation.memory, config.max_memory_mb) {
+        if memory >= max * 1024 * 1024 {
+            problems.push(format!("it uses {} MiB of memory", memory / 1024 / 1024));
+        }
+    }
+
+    problems
+}
+
+/// How many files a process has open.
+fn open_files(pid: Pid) -> Option<usize> {
+    Some(std::fs::read_dir(format!("/proc/{pid}/fd")).ok()?.count())
+}
+
+/// How much of a process's memory is resident, in bytes.
+fn memory(pid: Pid) -> Option<u64> {
+    let statm = std::fs::read_to_string(format!("/proc/{pid}/statm")).ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = nix::unistd::sysconf(SysconfVar::PAGE_SIZE).ok()??;
+    Some(pages * page_size as u64)
+}
+
+#[test]
+fn test() {
+    let config = Config {
+        restart_interval_secs: 0,
+        max_heartbeat_age_secs: 60,
+        max_display_errors_per_minute: 5,
+        max_open_files: 100,
+        max_memory_mb: 10,
+        ..Default::default()
+    };
+    let health = Health {
+        heartbeats: BTreeMap::from([("default CLIPBOARD".to_string(), Some(1.0))]),
+        errors: BTreeMap::from([(":1".to_string(), 1)]),
+    };
+    let healthy = Observation {
+        age: Duration::from_secs(600),
+        report: Some((Duration::from_secs(1), health.clone())),
+        paused: false,
+        open_files: Some(10),
+        memory: Some(1024 * 1024),
+    };
+    assert!(problems(&config, &healthy).is_empty());
+
+    let mut stalled = health.clone();
+    stalled
+        .heartbeats
+        .insert("default PRIMARY".to_string(), Some(90.0));
+    stalled.errors.insert(":2".to_string(), 5);
+    let unhealthy = Observation {
+        report: Some((Duration::from_secs(1), stalled)),
+        open_files: Some(100),
+        memory: Some(20 * 1024 * 1024),
+        ..healthy
+    };
+    assert_eq!(
+        vec![
+            "default PRIMARY has not synced for 90s",
+            ":2 stopped responding 5 times in a minute",
+            "it has 100 open files",
+            "it uses 20 MiB of memory",
+        ],
+        problems(&config, &unhealthy)
+    );
+
+    // a child that's stuck can't report, and a new one gets time to start
+    let silent = Observation {
+        age: Duration::from_secs(600),
+        ..Default::default()
+    };
+    assert_eq!(1, problems(&config, &silent).len());
+    let starting = Observation {
+        age: Duration::from_secs(5),
+        ..Default::default()
+    };
+    assert!(problems(&config, &starting).is_empty());
+    let paused = Observation {
+        age: Duration::from_secs(600),
+        paused: true,
+        ..Default::default()
+    };
+    assert!(problems(&config, &paused).is_empty());
+
+    let this = nix::unistd::getpid();
+    assert!(open_files(this).unwrap() > 0);
+    assert!(memory(this).unwrap() > 0);
+}
@@ -0,0 +1,265 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clipboard::Selection;
+use crate::config::Config;
+use crate::content::ClipboardContent;
+use crate::error::{MyError, MyResult};
+use crate::log;
+
+/// A value that was synced, as it's stored on disk.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub id: u64,
+    pub time: DateTime<Local>,
+    /// the sync group that the value was copied in
+    #[serde(default = "default_group")]
+    pub group: String,
+    pub selection: Selection,
+    /// the display that the value was copied from
+    pub display: String,
+    pub content: ClipboardContent,
+}
+
+/// Describes an Entry without including its contents.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub id: u64,
+    pub time: DateTime<Local>,
+    pub group: String,
+    pub selection: Selection,
+    pub display: String,
+    pub mime_types: Vec<String>,
+    pub size: usize,
+}
+
+impl Entry {
+    pub fn summary(&self) -> Summary {
+        Summary {
+            id: self.id,
+            time: self.time,
+            group: self.group.clone(),
+            selection: self.selection,
+            display: self.display.clone(),
+            mime_types: self.content.mime_types(),
+            size: self.content.size(),
+        }
+    }
+}
+
+/// Records every synced value in a directory, with one json file per entry.
+/// Since the clipboard contents are sensitive, only the user can read them.
+///
+/// Nothing is kept in memory, so the files can be shared by every process
+/// that uses the same directory.
+pub struct History {
+    dir: PathBuf,
+    max_entries: usize,
+    max_age: Duration,
+    max_bytes: u64,
+    /// held while writing, so concurrent records get distinct ids
+    writing: Mutex<()>,
+}
+
+impl History {
+    /// None if the history is disabled, or there is nowhere to store it.
+    pub fn new(config: &Config) -> Option<Self> {
+        if config.history_max_entries == 0 {
+            return None;
+        }
+        let Some(dir) = config.history_dir.clone().or_else(default_dir) else {
+            log::warning!("clipboard history is disabled because there is no data directory");
+            return None;
+        };
+
+        Some(Self {
+            dir,
+            max_entries: config.history_max_entries,
+            max_age: Duration::from_secs(config.history_max_age_secs),
+            max_bytes: config.history_max_bytes,
+            writing: Mutex::new(()),
+        })
+    }
+
+    pub fn record(
+        &self,
+        group: &str,
+        selection: Selection,
+        display: &str,
+        content: &ClipboardContent,
+    ) -> MyResult<()> {
+        if content.is_secret() {
+            log::debug!("not saving a secret to the clipboard history");
+            return Ok(());
+        }
+        let _writing = self.writing.lock()?;
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        let id = self.ids()?.last().map(|id| id + 1).unwrap_or(0);
+        let entry = Entry {
+            id,
+            time: Local::now(),
+            group: group.to_string(),
+            selection,
+            display: display.to_string(),
+            content: content.clone(),
+        };
+        let temp = self.dir.join(format!(".{id}.json"));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp)?;
+        serde_json::to_writer(&mut file, &entry)?;
+        file.flush()?;
+        fs::rename(&temp, self.path(id))?;
+        log::debug!("saved clipboard history entry {id}");
+        self.prune()
+    }
+
+    /// The entries from oldest to newest.
+    pub fn list(&self) -> MyResult<Vec<Entry>> {
+        self.ids()?.into_iter().map(|id| self.get(id)).collect()
+    }
+
+    pub fn get(&self, id: u64) -> MyResult<Entry> {
+        let data = fs::read(self.path(id)).map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => MyError::NoHistoryEntry(id),
+            _ => err.into(),
+        })?;
+
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    pub fn clear(&self) -> MyResult<()> {
+        let _writing = self.writing.lock()?;
+        for id in self.ids()? {
+            fs::remove_file(self.path(id))?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the oldest entries until the limits are satisfied. Each file is
+    /// written once, so its modification time is when the entry was saved, and
+    /// the entries don't need to be read.
+    fn prune(&self) -> MyResult<()> {
+        let ids = self.ids()?;
+        let mut total_bytes = 0;
+        let mut kept = 0;
+        for id in ids.into_iter().rev() {
+            let path = self.path(id);
+            let metadata = fs::metadata(&path)?;
+            let bytes = metadata.len();
+            let expired = metadata
+                .modified()?
+                .elapsed()
+                .is_ok_and(|age| age > self.max_age);
+            if expired || kept >= self.max_entries || total_bytes + bytes > self.max_bytes {
+                log::debug!("removing clipboard history entry {id}");
+                fs::remove_file(path)?;
+            } else {
+                total_bytes += bytes;
+                kept += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The ids of the stored entries, in ascending order.
+    fn ids(&self) -> MyResult<Vec<u64>> {
+        let read_dir = match fs::read_dir(&self.dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut ids = vec![];
+        for file in read_dir {
+            let name = file?.file_name();
+            let id = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".json"))
+                .and_then(|id| id.parse::<u64>().ok());
+            ids.extend(id);
+        }
+        ids.sort();
+
+        Ok(ids)
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+}
+
+/// Entries that were saved before there were groups belong to the default
+/// group.
+fn default_group() -> String {
+    Config::default().default_group
+}
+
+/// $XDG_DATA_HOME/clipboard-sync/history, where XDG_DATA_HOME defaults to
+/// ~/.local/share
+fn default_dir() -> Option<PathBuf> {
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(Path::new(&std::env::var_os("HOME")?).join(".local/share")))?;
+
+    Some(data_home.join("clipboard-sync").join("history"))
+}
+
+#[test]
+fn test() {
+    let dir = std::env::temp_dir().join(format!("clipboard-sync-history-{}", std::process::id()));
+    let config = Config {
+        history_dir: Some(dir.clone()),
+        history_max_entries: 2,
+        ..Default::default()
+    };
+    let history = History::new(&config).unwrap();
+    for text in ["one", "two", "three"] {
+        let content = ClipboardContent::from_text(text);
+        history
+            .record("default", Selection::Clipboard, ":0", &content)
+            .unwrap();
+    }
+    let mut secret = ClipboardContent::from_text("password");
+    secret.insert("x-kde-passwordManagerHint", b"secret".to_vec());
+    history
+        .record("default", Selection::Clipboard, ":0", &secret)
+        .unwrap();
+    let entries = history.list().unwrap();
+    assert_eq!(vec![1, 2], entries.iter().map(|e| e.id).collect::<Vec<_>>());
+    assert_eq!(Some("three".to_string()), entries[1].content.text());
+    assert_eq!(":0", history.get(1).unwrap().display);
+    assert!(matches!(history.get(0), Err(MyError::NoHistoryEntry(0))));
+
+    // entries expire based on when their files were written
+    let old = fs::File::options()
+        .write(true)
+        .open(history.path(2))
+        .unwrap();
+    old.set_modified(std::time::SystemTime::now() - Duration::from_secs(8 * 24 * 60 * 60))
+        .unwrap();
+    let content = ClipboardContent::from_text("four");
+    history
+        .record("default", Selection::Clipboard, ":0", &content)
+        .unwrap();
+    let entries = history.list().unwrap();
+    assert_eq!(vec![1, 3], entries.iter().map(|e| e.id).collect::<Vec<_>>());
+
+    history.clear().unwrap();
+    assert!(history.list().unwrap().is_empty());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,195 @@
+use chrono::Local;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::MyResult;
+use crate::log;
+use crate::tmux;
+
+/// Where x11 servers create their sockets, named X0, X1, ...
+const X11_SOCKET_DIR: &str = "/tmp/.X11-unix";
+
+/// How long a new display has to become usable after its socket appears.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the watcher thread checks whether it's still needed.
+const ALIVE_CHECK_MS: i32 = 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisplayEvent {
+    /// a display started, and it's ready to be synced
+    Added(String),
+    /// a display's socket was removed
+    Removed(String),
+}
+
+/// Watches the directories where wayland compositors, x11 servers, and tmux
+/// servers create their sockets, to notice displays that start or stop while
+/// syncing.
+///
+/// The events are collected until they are taken by the sync loop, which is
+/// woken up by an empty display name sent to the channel that it waits on. The
+/// watcher thread exits soon after the Hotplug is dropped.
+pub struct Hotplug {
+    events: Arc<Mutex<Vec<DisplayEvent>>>,
+}
+
+impl Hotplug {
+    /// `accept` decides which display names are relevant, and `probe` checks
+    /// whether a new display is ready to be synced. New displays are probed
+    /// repeatedly until they are ready, since the socket is often created
+    /// before the display is fully set up.
+    pub fn watch(
+        accept: impl Fn(&str) -> bool + Send + 'static,
+        probe: impl Fn(&str) -> bool + Send + 'static,
+        wake: Sender<String>,
+    ) -> MyResult<Self> {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)?;
+        let flags = AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_TO
+            | AddWatchFlags::IN_MOVED_FROM;
+        let dirs = std::env::var_os("XDG_RUNTIME_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .into_iter()
+            .chain([PathBuf::from(X11_SOCKET_DIR)]);
+        for dir in dirs {
+            if let Err(err) = inotify.add_watch(&dir, flags) {
+                log::debug!("not watching {} for new displays: {err}", dir.display());
+            }
+        }
+        // tmux creates its directory the same way when its first server
+        // starts, so it's created in advance to be watched
+        let tmux_dir = tmux::socket_dir();
+        let _ = std::fs::DirBuilder::new().mode(0o700).create(&tmux_dir);
+        let tmux_watch = inotify
+            .add_watch(&tmux_dir, flags)
+            .map_err(|err| {
+                log::debug!(
+                    "not watching {} for new tmux servers: {err}",
+                    tmux_dir.display()
+                )
+            })
+            .ok();
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let weak = Arc::downgrade(&events);
+        thread::Builder::new()
+            .name("hotplug".to_string())
+            .spawn(move || {
+                if let Err(err) = watch(inotify, tmux_watch, weak, accept, probe, wake) {
+                    log::error!("stopped watching for new displays: {err}");
+                }
+                let _ = nix::unistd::close(inotify.as_raw_fd());
+            })?;
+
+        Ok(Self { events })
+    }
+
+    pub fn pending(&self) -> bool {
+        !self.events.lock().unwrap().is_empty()
+    }
+
+    pub fn take(&self) -> Vec<DisplayEvent> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+fn watch(
+    inotify: Inotify,
+    tmux_watch: Option<WatchDescriptor>,
+    events: Weak<Mutex<Vec<DisplayEvent>>>,
+    accept: impl Fn(&str) -> bool,
+    probe: impl Fn(&str) -> bool,
+    wake: Sender<String>,
+) -> MyResult<()> {
+    loop {
+        let mut fds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+        poll(&mut fds, ALIVE_CHECK_MS)?;
+        if events.strong_count() == 0 {
+            return Ok(());
+        }
+        if fds[0].revents().unwrap_or(PollFlags::empty()).is_empty() {
+            continue;
+        }
+        for event in inotify.read_events()? {
+            let Some(file_name) = event.name.as_ref().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let name = match Some(event.wd) == tmux_watch {
+                true if tmux::is_socket(file_name) => Some(format!("{}{file_name}", tmux::PREFIX)),
+                true => None,
+                false => display_name(file_name),
+            };
+            let Some(name) = name else {
+                continue;
+            };
+            if !accept(&name) {
+                continue;
+            }
+            let removed = event
+                .mask
+                .intersects(AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM);
+            let display_event = if removed {
+                log::debug!(
+                    event = "hotplug_removed", display = name;
+                    "display {name} was removed"
+                );
+                DisplayEvent::Removed(name)
+            } else {
+                log::debug!("display {name} appeared, waiting for it to be ready");
+                let deadline = Instant::now() + PROBE_TIMEOUT;
+                let ready = loop {
+                    if probe(&name) {
+                        break true;
+                    }
+                    if Instant::now() >= deadline {
+                        break false;
+                    }
+                    thread::sleep(PROBE_INTERVAL);
+                };
+                if !ready {
+                    log::warning!("display {name} appeared, but it can't be synced");
+                    continue;
+                }
+                log::debug!(event = "hotplug_added", display = name; "display {name} is ready");
+                DisplayEvent::Added(name)
+            };
+            let Some(events) = events.upgrade() else {
+                return Ok(());
+            };
+            events.lock().unwrap().push(display_event);
+            if wake.send(String::new()).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The display name for a socket file, like "wayland-1" or ":0".
+fn display_name(file_name: &str) -> Option<String> {
+    if let Some(n) = file_name.strip_prefix("wayland-") {
+        n.parse::<u8>().ok()?;
+        Some(file_name.to_string())
+    } else {
+        let n = file_name.strip_prefix('X')?.parse::<u8>().ok()?;
+        Some(format!(":{n}"))
+    }
+}
+
+#[test]
+fn test() {
+    assert_eq!(Some("wayland-1".to_string()), display_name("wayland-1"));
+    assert_eq!(None, display_name("wayland-1.lock"));
+    assert_eq!(Some(":12".to_string()), display_name("X12"));
+    assert_eq!(None, display_name("bus"));
+}
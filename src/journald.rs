@@ -0,0 +1,139 @@
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+use serde_json::Value;
+
+/// Where journald receives entries in its native protocol.
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Connects to the journal.
+pub fn connect() -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(SOCKET)?;
+    Ok(socket)
+}
+
+/// Whether stdout or stderr is connected to the journal, like when running as
+/// a systemd service. systemd sets JOURNAL_STREAM to the device and inode of
+/// that stream, so it's not inherited by mistake from a parent process.
+pub fn detected() -> bool {
+    let Some(stream) = std::env::var_os("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Some((dev, ino)) = stream.to_str().and_then(|s| s.split_once(':')) else {
+        return false;
+    };
+    [1, 2]
+        .into_iter()
+        .any(|fd| match nix::sys::stat::fstat(fd) {
+            Ok(stat) => dev == stat.st_dev.to_string() && ino == stat.st_ino.to_string(),
+            Err(_) => false,
+        })
+}
+
+/// Writes an entry to the journal.
+pub fn send(
+    socket: &UnixDatagram,
+    level: &str,
+    message: &str,
+    fields: &[(&str, Value)],
+) -> io::Result<()> {
+    socket.send(&encode(level, message, fields))?;
+    Ok(())
+}
+
+/// Encodes an entry in journald's native protocol: a line of NAME=value for
+/// each field, or the name, the length, and the value for values that
+/// contain newlines.
+fn encode(level: &str, message: &str, fields: &[(&str, Value)]) -> Vec<u8> {
+    let mut entry = vec![];
+    let mut add = |name: &str, value: &str| {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    };
+    add("MESSAGE", message);
+    add("PRIORITY", priority(level));
+    add("SYSLOG_IDENTIFIER", "clipboard-sync");
+    if let Some(thread) = std::thread::current().name() {
+        add("THREAD_NAME", thread);
+    }
+    for (key, value) in fields {
+        let value = match value {
+            Value::Null => continue,
+            Value::String(s) => s.clone(),
+            // an error and its sources
+            Value::Array(values) => values
+                .iter()
+                .map(|v| v.as_str().map(str::to_string).unwrap_or(v.to_string()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            value => value.to_string(),
+        };
+        add(&field_name(key), &value);
+    }
+
+    entry
+}
+
+/// The syslog priority of a log level.
+fn priority(level: &str) -> &'static str {
+    match level.trim() {
+        "FATAL" => "2",
+        "ERROR" => "3",
+        "WARN" => "4",
+        "INFO" => "6",
+        _ => "7",
+    }
+}
+
+/// The journal field for a log field. Some are renamed so they don't look
+/// like they belong to systemd or to the environment, like DISPLAY.
+fn field_name(key: &str) -> String {
+    match key {
+        "display" => "DISPLAY_NAME".to_string(),
+        "origin" => "SYNC_ORIGIN".to_string(),
+        key => key.to_uppercase(),
+    }
+}
+
+#[test]
+fn test() {
+    let fields = vec![
+        ("display", Value::from(":1")),
+        ("origin", Value::Null),
+        ("error", Value::from(vec!["timed out", "reason"])),
+        ("error_kind", Value::from("Timeout")),
+    ];
+    let entry = encode(" WARN", "skipping :1", &fields);
+    let mut expected =
+        b"MESSAGE=skipping :1\nPRIORITY=4\nSYSLOG_IDENTIFIER=clipboard-sync\n".to_vec();
+    if let Some(thread) = std::thread::current().name() {
+        expected.extend_from_slice(format!("THREAD_NAME={thread}\n").as_bytes());
+    }
+    expected.extend_from_slice(b"DISPLAY_NAME=:1\nERROR\n");
+    expected.extend_from_slice(&16u64.to_le_bytes());
+    expected.extend_from_slice(b"timed out\nreason\nERROR_KIND=Timeout\n");
+    assert_eq!(
+        String::from_utf8_lossy(&expected),
+        String::from_utf8_lossy(&entry)
+    );
+
+    // a socket that stands in for the journal
+    let dir = std::env::temp_dir().join(format!("clipboard-sync-journal-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let journal = UnixDatagram::bind(dir.join("socket")).unwrap();
+    let socket = UnixDatagram::unbound().unwrap();
+    socket.connect(dir.join("socket")).unwrap();
+    send(&socket, "ERROR", "failed", &[]).unwrap();
+    let mut buf = [0; 1024];
+    let n = journal.recv(&mut buf).unwrap();
+    assert!(buf[..n].starts_with(b"MESSAGE=failed\nPRIORITY=3\n"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
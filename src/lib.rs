@@ -0,0 +1,49 @@
+//! Synchronizes the clipboard across multiple X11 and wayland instances running
+//! on the same machine, and optionally with tmux, terminals, and other
+//! machines.
+//!
+//! Every kind of clipboard implements [`clipboard::Clipboard`].
+//! [`sync::get_clipboards`] finds the clipboards of a group of displays, and
+//! [`sync::keep_synced`] copies changes between them. [`SyncEngine`] does both
+//! for every group and selection, like the clipboard-sync daemon:
+//!
+//! ```no_run
+//! use clipboard_sync::{config::Config, control::Event, SyncEngine};
+//!
+//! let engine = SyncEngine::builder()
+//!     .config(Config::default())
+//!     .on_event(|event| {
+//!         if let Event::Synced { display, .. } = event {
+//!             println!("copied from {display}");
+//!         }
+//!     })
+//!     .build();
+//! engine.run().unwrap();
+//! ```
+
+pub mod clipboard;
+pub mod clock;
+pub mod config;
+pub mod content;
+pub mod control;
+mod convert;
+pub mod dbus;
+mod engine;
+pub mod error;
+mod health;
+pub mod history;
+mod hotplug;
+mod journald;
+pub mod log;
+#[cfg(test)]
+mod mock;
+mod mustatex;
+pub mod osc52;
+pub mod peer;
+pub mod sync;
+mod systemd;
+pub mod tmux;
+mod wayland;
+mod x11;
+
+pub use engine::{SyncEngine, SyncEngineBuilder};
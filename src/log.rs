@@ -2,9 +2,23 @@ mustatex! {
     pub(crate) level: Level = Level::Debug;
     pub(crate) timestamp: bool = true;
     pub(crate) log_sensitive_information: bool = false;
+    pub(crate) format: Format = Format::Text;
+    pub(crate) journal: Option<UnixDatagram> = None;
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
 pub enum Level {
     Fatal,
     Error,
@@ -23,14 +37,46 @@ impl Default for Level {
     }
 }
 
+/// How each log line is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `timestamp - LEVEL - message`, for people
+    #[default]
+    Text,
+    /// one json object per line, for log pipelines
+    Json,
+}
+
+/// Where logs are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    /// the journal when running as a systemd service, otherwise the console
+    #[default]
+    Auto,
+    /// stdout and stderr
+    Console,
+    /// the systemd journal, with each field as a journal field
+    Journald,
+}
+
 /// This is for logging of sensitive information that you usually don't want to
 /// log in production, regardless of log level. It won't log anything unless
 /// `sensitive` is set to true.  
 /// In this crate, it's used to log clipboard contents.
+///
+/// Like the other macros, it accepts fields for the json format before a `;`,
+/// which are only logged under the same condition.
 macro_rules! sensitive {
+	($log_macro:path, $($key:ident = $value:expr),+ ; $($arg:tt)*) => {
+		if *crate::log::log_sensitive_information::get() {
+			$log_macro!(sensitive = true, $($key = $value),+ ; "Sensitive: {}", format!($($arg)*))
+		}
+	};
 	($log_macro:path, $($arg:tt)*) => {
 		if *crate::log::log_sensitive_information::get() {
-			$log_macro!("Sensitive: {}", format!($($arg)*))
+			$log_macro!(sensitive = true ; "Sensitive: {}", format!($($arg)*))
 		}
 	};
 }
@@ -92,13 +138,20 @@ macro_rules! fatal {
 }
 pub(crate) use fatal;
 
+/// Every log macro optionally takes `key = value` fields before a `;`, like
+/// `log::info!(event = "synced", display = name; "...")`. They are only
+/// written in the json format, so the message should make sense without them.
 macro_rules! _log {
+	($print:ident, $level:literal, $($key:ident = $value:expr),+ ; $($arg:tt)*) => {{
+		let mut fields = vec![];
+		$(crate::log::Field::push(&$value, stringify!($key), &mut fields);)+
+		if let Some(line) = crate::log::record(Local::now(), $level, format!($($arg)*), fields) {
+			$print!("{line}");
+		}
+	}};
 	($print:ident, $level:literal, $($arg:tt)*) => {{
-		let s = format!($($arg)*);
-		if *crate::log::timestamp::get() {
-			$print!("{} - {} - {}", Local::now().format("%Y-%m-%d %H:%M:%S"), $level, s);
-		} else {
-			$print!("{} - {}", $level, s);
+		if let Some(line) = crate::log::record(Local::now(), $level, format!($($arg)*), vec![]) {
+			$print!("{line}");
 		}
 	}};
 }
@@ -119,8 +172,178 @@ macro_rules! truncate_to_debug {
 #[allow(unused)]
 pub(crate) use truncate_to_debug;
 
+use std::os::unix::net::UnixDatagram;
+
+use chrono::{DateTime, Local};
+use serde_json::{Map, Value};
+
+use crate::clipboard::{self, Selection};
+use crate::config::Config;
+use crate::content::ClipboardContent;
+use crate::error::MyError;
+use crate::journald;
 use crate::mustatex::mustatex;
 
+/// Applies the logging settings from the config.
+pub fn configure(config: &Config) {
+    level::set(config.log_level);
+    timestamp::set(!config.hide_timestamp);
+    log_sensitive_information::set(config.log_clipboard_contents);
+    format::set(config.log_format);
+    let journal = match config.log_target {
+        Target::Auto if journald::detected() => journald::connect().ok(),
+        Target::Auto | Target::Console => None,
+        Target::Journald => journald::connect()
+            .map_err(|err| warning!("not logging to the journal: {err}"))
+            .ok(),
+    };
+    journal::set(journal);
+}
+
+/// Sends a log record to the journal if it's being used, and otherwise
+/// returns the line to print. Records that the journal doesn't accept are
+/// printed instead.
+pub fn record(
+    time: DateTime<Local>,
+    level: &str,
+    message: String,
+    fields: Vec<(&str, Value)>,
+) -> Option<String> {
+    if let Some(journal) = &*journal::get() {
+        let journal_fields = with_backend(fields.clone());
+        if journald::send(journal, level, &message, &journal_fields).is_ok() {
+            return None;
+        }
+    }
+    Some(line(time, level, message, fields))
+}
+
+/// Formats a log line in the configured format.
+pub fn line(
+    time: DateTime<Local>,
+    level: &str,
+    message: String,
+    fields: Vec<(&str, Value)>,
+) -> String {
+    let timestamp = *timestamp::get();
+    if *format::get() == Format::Text {
+        return match timestamp {
+            true => format!("{} - {level} - {message}", time.format("%Y-%m-%d %H:%M:%S")),
+            false => format!("{level} - {message}"),
+        };
+    }
+    let mut object = Map::new();
+    if timestamp {
+        object.insert("timestamp".into(), time.to_rfc3339().into());
+    }
+    object.insert("level".into(), level.trim().to_lowercase().into());
+    object.insert("message".into(), message.into());
+    if let Some(thread) = std::thread::current().name() {
+        object.insert("thread".into(), thread.into());
+    }
+    for (key, value) in with_backend(fields) {
+        object.insert(key.into(), value);
+    }
+    Value::Object(object).to_string()
+}
+
+/// Adds the backend of the display, if there is one.
+fn with_backend(mut fields: Vec<(&str, Value)>) -> Vec<(&str, Value)> {
+    let backend = fields.iter().find_map(|(key, value)| match (*key, value) {
+        ("display", Value::String(display)) => Some(clipboard::backend(display)),
+        _ => None,
+    });
+    if let Some(backend) = backend {
+        fields.push(("backend", backend.into()));
+    }
+    fields
+}
+
+/// A value that can be logged as a field in the json format.
+pub trait Field {
+    fn json(&self) -> Value;
+
+    /// Adds this value to the fields of a record as `key`, along with any
+    /// fields that are derived from it.
+    fn push<'a>(&self, key: &'a str, fields: &mut Vec<(&'a str, Value)>) {
+        fields.push((key, self.json()));
+    }
+}
+
+impl<T: Field + ?Sized> Field for &T {
+    fn json(&self) -> Value {
+        (**self).json()
+    }
+
+    fn push<'a>(&self, key: &'a str, fields: &mut Vec<(&'a str, Value)>) {
+        (**self).push(key, fields)
+    }
+}
+
+impl<T: Field> Field for Option<T> {
+    fn json(&self) -> Value {
+        self.as_ref().map(T::json).unwrap_or_default()
+    }
+
+    fn push<'a>(&self, key: &'a str, fields: &mut Vec<(&'a str, Value)>) {
+        match self {
+            Some(value) => value.push(key, fields),
+            None => fields.push((key, Value::Null)),
+        }
+    }
+}
+
+impl Field for str {
+    fn json(&self) -> Value {
+        self.into()
+    }
+}
+
+impl Field for String {
+    fn json(&self) -> Value {
+        self.as_str().into()
+    }
+}
+
+impl Field for bool {
+    fn json(&self) -> Value {
+        (*self).into()
+    }
+}
+
+impl Field for Selection {
+    fn json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+impl Field for ClipboardContent {
+    fn json(&self) -> Value {
+        self.to_string().into()
+    }
+}
+
+/// The error followed by each of its sources.
+impl Field for MyError {
+    fn json(&self) -> Value {
+        let mut chain = vec![Value::from(self.to_string())];
+        let mut source = std::error::Error::source(self);
+        while let Some(err) = source {
+            chain.push(err.to_string().into());
+            source = err.source();
+        }
+        chain.into()
+    }
+
+    /// Also adds `error_kind`, the name of the variant.
+    fn push<'a>(&self, key: &'a str, fields: &mut Vec<(&'a str, Value)>) {
+        fields.push((key, self.json()));
+        let debug = format!("{self:?}");
+        let kind = debug.split(|c: char| !c.is_alphanumeric()).next();
+        fields.push(("error_kind", kind.unwrap_or_default().into()));
+    }
+}
+
 #[allow(unused)]
 pub fn truncate(s: &str, max_chars: usize) -> &str {
     match s.char_indices().nth(max_chars) {
@@ -139,7 +362,7 @@ pub fn concise_numbers(ns: &[u8]) -> String {
     let mut range_size = 1;
     let mut strings = vec![];
     for nn in ns.windows(2) {
-        let [n1, n2]: [u8] = *nn else {unreachable!()};
+        let [n1, n2]: [u8] = *nn else { unreachable!() };
         if n1 + 1 == n2 {
             range_size += 1;
         } else {
@@ -156,7 +379,7 @@ pub fn concise_numbers(ns: &[u8]) -> String {
     strings.push("..".to_owned());
     let mut full_strings = vec![];
     for ss in strings.windows(2) {
-        let [s1, s2] = ss else {unreachable!()};
+        let [s1, s2] = ss else { unreachable!() };
         full_strings.push(s1.to_owned());
         if s1 != ".." && s2 != ".." {
             full_strings.push(", ".to_owned());
@@ -190,4 +413,20 @@ fn test() {
         "[0, 1, 3, 4, 6..8, 10]",
         concise_numbers(&[0, 1, 3, 4, 6, 7, 8, 10])
     );
+    format::set(Format::Json);
+    let err = MyError::ConfigRead {
+        path: "config.toml".into(),
+        source: std::io::Error::other("broken pipe"),
+    };
+    let mut fields = vec![("display", ":1".json())];
+    err.push("error", &mut fields);
+    let line = line(Local::now(), " WARN", "skipping :1".into(), fields);
+    format::set(Format::Text);
+    let object: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!("warn", object["level"]);
+    assert_eq!("skipping :1", object["message"]);
+    assert_eq!("x11", object["backend"]);
+    assert_eq!(err.to_string(), object["error"][0]);
+    assert_eq!("broken pipe", object["error"][1]);
+    assert_eq!("ConfigRead", object["error_kind"]);
 }
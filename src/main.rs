@@ -1,30 +1,57 @@
-use chrono::Local;
 use clap::Parser;
-use error::MyResult;
-use nix::sys::signal::{self, Signal};
-use nix::sys::wait::{WaitPidFlag, WaitStatus};
-use nix::unistd::{fork, Pid};
-use nix::{sys::wait::waitpid, unistd::ForkResult};
-use std::f64::consts::E;
-use std::thread;
-use std::time::SystemTime;
-use std::{thread::sleep, time::Duration};
-
-use crate::error::MyError;
-
-mod clipboard;
-mod error;
-mod log;
-mod mustatex;
-mod sync;
+use clipboard_sync::config::{self, Config};
+use clipboard_sync::control::{self, Request, Response};
+use clipboard_sync::{log, peer, SyncEngine};
+use std::path::PathBuf;
 
 fn main() {
     let args = Args::parse();
-    configure_logging(&args);
-    if args.run_forked {
-        run_forked()
-    } else {
-        run()
+    if let Some(request) = &args.command {
+        send_request(request);
+        return;
+    }
+    let config = match Config::load(args.config.as_deref()) {
+        Ok(config) => args.override_config(config),
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = peer::check(&config) {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+    if args.check_config {
+        match args.config.clone().or_else(config::default_path) {
+            Some(path) if path.exists() => println!("{} is valid", path.display()),
+            _ => println!("no config file found, so the defaults will be used"),
+        }
+        return;
+    }
+    log::configure(&config);
+    let run_forked = config.run_forked;
+    let engine = SyncEngine::builder().config(config).build();
+    if run_forked {
+        engine.run_forked()
+    } else if let Err(err) = engine.run() {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
+/// Sends a subcommand to the running daemon and prints its json response.
+fn send_request(request: &Request) {
+    match control::send(request) {
+        Ok(response) => {
+            println!("{}", serde_json::to_string(&response).unwrap());
+            if let Response::Error { .. } = response {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
     }
 }
 
@@ -32,144 +59,93 @@ fn main() {
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None, max_term_width = 120)]
 struct Args {
-    /// granularity to log
-    #[arg(long, value_enum, default_value_t = log::Level::default())]
-    log_level: log::Level,
+    /// when a command is given, it's sent to the clipboard-sync that is
+    /// already running, instead of starting a new one
+    #[command(subcommand)]
+    command: Option<Request>,
 
-    /// whether to include timestamps in the logs (systemd already includes
-    /// timestamps so you'll want to enable this for systemd)
+    /// path to the config file [default:
+    /// $XDG_CONFIG_HOME/clipboard-sync/config.toml]
     #[arg(long)]
-    hide_timestamp: bool,
+    config: Option<PathBuf>,
 
-    /// whether to run the sync forked so the state can be cleaned up
-    /// periodically. typically, this should be true.
+    /// check that the config file is valid, then exit
     #[arg(long)]
-    #[cfg_attr(not(debug_assertions), arg(default_value_t = true))]
-    run_forked: bool,
+    check_config: bool,
+
+    /// granularity to log [default: info, or debug in debug builds]
+    #[arg(long, value_enum)]
+    log_level: Option<log::Level>,
+
+    /// whether to include timestamps in the logs (systemd already includes
+    /// timestamps so you'll want to enable this for systemd) [default: false]
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    hide_timestamp: Option<bool>,
+
+    /// write logs as text, or as one json object per line with fields like
+    /// the display and its backend [default: text]
+    #[arg(long, value_enum)]
+    log_format: Option<log::Format>,
+
+    /// where to write logs. by default, they are written to the journal when
+    /// running as a systemd service, with fields that can be filtered on,
+    /// like `journalctl --user -u clipboard-sync DISPLAY_NAME=:1` [default:
+    /// auto]
+    #[arg(long, value_enum)]
+    log_target: Option<log::Target>,
+
+    /// whether to run the sync forked so the state can be cleaned up by
+    /// restarting it when it's unhealthy. typically, this should be true. [default: true, or
+    /// false in debug builds]
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    run_forked: Option<bool>,
 
     /// when debug logging is enabled, it won't show clipboard contents, because
     /// clipboard contents are sensitive user information. but if you set this
     /// to true, in addition to enabling debug logging, then it will log the
-    /// clipboard contents.
-    #[arg(long)]
-    log_clipboard_contents: bool,
-}
+    /// clipboard contents. [default: false]
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    log_clipboard_contents: Option<bool>,
 
-fn configure_logging(args: &Args) {
-    log::level::set(args.log_level);
-    log::timestamp::set(!args.hide_timestamp);
-    log::log_sensitive_information::set(args.log_clipboard_contents);
-}
-
-#[allow(dead_code)]
-fn run_forked() {
-    log::info!("started clipboard sync manager");
-    let mut panics = 0;
-    loop {
-        match unsafe { fork() }.expect("Failed to fork") {
-            ForkResult::Parent { child } => {
-                log::debug!("child process {child} successfully initialized.");
-                kill_after(child, 600);
-                let status = waitpid(Some(child), None)
-                    .expect("there was a problem managing the child process, so the service is exiting. check that pid {child} is not running before restarting this service");
-                log::debug!("child process {child} completed with: {status:?}");
-                if let WaitStatus::Exited(_, 101) = status {
-                    panics += 1;
-                    if panics < 4 {
-                        log::fatal!("child process {child} panicked. giving it another try");
-                    } else {
-                        panic!("child process {child} panicked too many times.");
-                    }
-                }
-                sleep(Duration::from_secs(1));
-            }
-            ForkResult::Child => run(),
-        }
-    }
-}
+    /// also synchronize the primary selection (middle-click paste) across all
+    /// displays. it is synced as a separate channel, independently from the
+    /// regular clipboard. [default: false]
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    primary: Option<bool>,
 
-fn run() {
-    log::info!("starting clipboard sync");
-    loop_with_error_pain_management(
-        sync::get_clipboards().unwrap(),
-        |cb| sync::keep_synced(cb),
-        |_| sync::get_clipboards().unwrap(),
-    )
-    .unwrap();
+    /// also sync the clipboard of the terminal on this tty using OSC 52
+    /// escape sequences, for example `--osc52 $(tty)` in an ssh session. can
+    /// be given more than once.
+    #[arg(long = "osc52", value_name = "TTY")]
+    terminals: Vec<PathBuf>,
 }
 
-pub fn kill_after(pid: Pid, seconds: u64) {
-    thread::spawn(move || {
-        log::debug!("waiting {seconds} seconds and then killing {pid}.");
-        thread::sleep(Duration::from_secs(seconds));
-        match waitpid(Some(pid), Some(WaitPidFlag::WNOHANG)) {
-            Ok(WaitStatus::StillAlive) => log::debug!("child {pid} is still alive, as expected."),
-            Ok(ok) => {
-                log::warning!("expected child process {pid} to be StillAlive but got: {ok:?}")
-            }
-            Err(e) => log::error!("error getting status of child process {pid}: {e}"),
+impl Args {
+    /// Flags that were passed on the command line replace the values from
+    /// the config file.
+    fn override_config(&self, mut config: Config) -> Config {
+        if let Some(level) = self.log_level {
+            config.log_level = level;
         }
-        log::debug!("routinely attempting to kill child process {pid}.");
-        if let Err(e) = signal::kill(pid, Signal::SIGTERM) {
-            log::error!("error killing child process {pid}: {e}")
+        if let Some(format) = self.log_format {
+            config.log_format = format;
         }
-    });
-}
-
-/// Execute an action with a sophisticated retry mechanism
-/// If the action fails:
-/// - 1. run a recovery step to manipulate the input
-/// - 2. attempt to execute the action again
-/// If the action fails too frequently, exit
-fn loop_with_error_pain_management<
-    Input,
-    Return,
-    Action: Fn(&Input) -> MyResult<Return>,
-    Recovery: Fn(Input) -> Input,
->(
-    // data passed into action and reset by recovery
-    initial_input: Input,
-    // action to attempt on every iteration
-    action: Action,
-    // action to attempt on every error - errors here are not yet handled. you can panic if necessary
-    recovery: Recovery,
-) -> MyResult<Return> {
-    let mut input = initial_input;
-    let mut error_times = vec![];
-    let mut errors = vec![];
-    loop {
-        match action(&input) {
-            Ok(ret) => return Ok(ret),
-            Err(err) => {
-                log::fatal!("action exited with error: {:?}", err);
-                let now = SystemTime::now();
-                error_times.push(now);
-                errors.push(err);
-                if total_pain(now, error_times.clone()) > 5.0 {
-                    return Err(MyError::Crash {
-                        msg: "too many errors, exiting".to_string(),
-                        cause: errors,
-                    });
-                }
-                input = recovery(input);
-                sleep(Duration::from_millis(1000));
-            }
+        if let Some(target) = self.log_target {
+            config.log_target = target;
+        }
+        if let Some(run_forked) = self.run_forked {
+            config.run_forked = run_forked;
+        }
+        if let Some(hide_timestamp) = self.hide_timestamp {
+            config.hide_timestamp = hide_timestamp;
+        }
+        if let Some(log_clipboard_contents) = self.log_clipboard_contents {
+            config.log_clipboard_contents = log_clipboard_contents;
         }
-        log::info!("retrying");
+        if let Some(primary) = self.primary {
+            config.primary = primary;
+        }
+        config.terminals.extend(self.terminals.iter().cloned());
+        config
     }
 }
-
-/// Sum the pain of numerous painful events, measured by how long ago they
-/// happened.
-fn total_pain(now: SystemTime, errors: Vec<SystemTime>) -> f64 {
-    errors
-        .into_iter()
-        .map(|et| remaining_pain(now.duration_since(et).unwrap().as_secs()))
-        .sum()
-}
-
-/// Looks at a painful event and determines how much of its pain is left.  
-/// calculated as exponential decay with a half-life of 1 minute.
-fn remaining_pain(seconds_ago: u64) -> f64 {
-    E.powf(-(seconds_ago as f64) / 86.561_702_453_337_8)
-}
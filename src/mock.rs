@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::clipboard::Clipboard;
+use crate::clock::Clock;
+use crate::content::ClipboardContent;
+use crate::error::{MyError, MyResult};
+
+/// A clipboard that only exists in memory, for tests. Reads and writes can be
+/// scripted to return other contents or to fail, and aliases share the same
+/// contents, like an x11 display and the wayland compositor that runs it.
+#[derive(Clone, Debug)]
+pub struct MockClipboard {
+    display: String,
+    /// shared with every alias
+    contents: Arc<Mutex<ClipboardContent>>,
+    watchers: Watchers,
+    script: Arc<Mutex<Script>>,
+}
+
+/// The display name of each watcher, and where to send it when the contents
+/// change.
+type Watchers = Arc<Mutex<Vec<(String, Sender<String>)>>>;
+
+#[derive(Debug, Default)]
+struct Script {
+    /// returned by the next reads, instead of the contents
+    reads: VecDeque<ClipboardContent>,
+    /// how many of the next calls fail
+    failures: usize,
+    /// everything that was written, oldest first
+    writes: Vec<ClipboardContent>,
+    /// whether it reports its own changes, or needs to be polled
+    watchable: bool,
+}
+
+impl MockClipboard {
+    pub fn new(display: &str) -> Self {
+        Self {
+            display: display.to_string(),
+            contents: Default::default(),
+            watchers: Default::default(),
+            script: Default::default(),
+        }
+    }
+
+    /// Reports its own changes instead of being polled.
+    pub fn watchable(self) -> Self {
+        self.script.lock().unwrap().watchable = true;
+        self
+    }
+
+    /// Another display with the same contents.
+    pub fn alias(&self, display: &str) -> Self {
+        Self {
+            display: display.to_string(),
+            contents: self.contents.clone(),
+            watchers: self.watchers.clone(),
+            script: Default::default(),
+        }
+    }
+
+    /// Copies `content` like a user would, telling the watchers about it.
+    pub fn copy(&self, content: ClipboardContent) {
+        *self.contents.lock().unwrap() = content;
+        for (display, watcher) in self.watchers.lock().unwrap().iter() {
+            let _ = watcher.send(display.clone());
+        }
+    }
+
+    /// Stops every watcher, like a display that lost its connection.
+    pub fn stop_watching(&self) {
+        self.watchers.lock().unwrap().clear();
+    }
+
+    pub fn contents(&self) -> ClipboardContent {
+        self.contents.lock().unwrap().clone()
+    }
+
+    /// Everything that was written to this display, oldest first.
+    pub fn writes(&self) -> Vec<ClipboardContent> {
+        self.script.lock().unwrap().writes.clone()
+    }
+
+    /// The next reads return `content` once each, regardless of the contents.
+    pub fn script_read(&self, content: ClipboardContent) {
+        self.script.lock().unwrap().reads.push_back(content);
+    }
+
+    /// The next `calls` reads or writes fail.
+    pub fn fail(&self, calls: usize) {
+        self.script.lock().unwrap().failures = calls;
+    }
+
+    fn check(&self, script: &mut Script) -> MyResult<()> {
+        if script.failures == 0 {
+            return Ok(());
+        }
+        script.failures -= 1;
+        Err(MyError::Unresponsive(self.display.clone()))
+    }
+}
+
+impl Clipboard for MockClipboard {
+    fn display(&self) -> String {
+        self.display.clone()
+    }
+
+    fn get(&self) -> MyResult<ClipboardContent> {
+        let mut script = self.script.lock().unwrap();
+        self.check(&mut script)?;
+        Ok(match script.reads.pop_front() {
+            Some(content) => content,
+            None => self.contents(),
+        })
+    }
+
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
+        let mut script = self.script.lock().unwrap();
+        self.check(&mut script)?;
+        script.writes.push(value.clone());
+        *self.contents.lock().unwrap() = value.clone();
+        Ok(())
+    }
+
+    fn watch(&self, notify: Sender<String>) -> MyResult<bool> {
+        if !self.script.lock().unwrap().watchable {
+            return Ok(false);
+        }
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.push((self.display.clone(), notify));
+        Ok(true)
+    }
+}
+
+/// Skips the rest of a test that needs `program`, which is not installed. With
+/// CLIPBOARD_SYNC_REQUIRE_TOOLS=1, like in CI, the test fails instead, so it
+/// can't pass without testing anything.
+pub fn skip(test: &str, program: &str) {
+    if std::env::var_os("CLIPBOARD_SYNC_REQUIRE_TOOLS").is_some_and(|v| v == "1") {
+        panic!("{test} needs {program}, which is not installed");
+    }
+    println!("skipping {test} because {program} is not installed");
+}
+
+/// A clock that only moves when something sleeps, or when it's advanced.
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+    advanced: Condvar,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(SystemTime::UNIX_EPOCH),
+            advanced: Condvar::new(),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+        self.advanced.notify_all();
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    /// Waits for real until a message arrives, or until the clock is advanced
+    /// past the timeout.
+    fn recv_timeout<T>(
+        &self,
+        receiver: &Receiver<T>,
+        timeout: Duration,
+    ) -> Result<T, RecvTimeoutError> {
+        let deadline = self.now() + timeout;
+        loop {
+            match receiver.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => (),
+            }
+            let now = self.now.lock().unwrap();
+            if *now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            // a message doesn't wake this up, so the receiver is checked often
+            drop(self.advanced.wait_timeout(now, Duration::from_millis(1)));
+        }
+    }
+}
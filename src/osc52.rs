@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine};
+use chrono::Local;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{self, LocalFlags, SetArg, SpecialCharacterIndices, Termios};
+
+use crate::clipboard::{Clipboard, Selection};
+use crate::content::ClipboardContent;
+use crate::error::{MyError, MyResult};
+use crate::log;
+use crate::mustatex::mustatex;
+
+// One lock for each tty, which is held while writing to it or waiting for its
+// reply, so the clipboard and primary selections of a terminal don't change
+// its settings or read each other's replies.
+mustatex! {
+    ttys: BTreeMap<PathBuf, Arc<Mutex<()>>> = BTreeMap::new();
+}
+
+/// Terminals are synced as displays named "osc52:" followed by the path of
+/// their tty, like "osc52:/dev/pts/3".
+pub const PREFIX: &str = "osc52:";
+
+/// How long to wait for a terminal to answer a query. Terminals that don't
+/// answer in time are never queried again.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often a terminal is queried at most. Each query briefly takes over the
+/// tty, so it's much less often than other displays are polled.
+const QUERY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The clipboard of a terminal emulator, which is copied to with OSC 52
+/// escape sequences written to its tty. If `query` is enabled, terminals that
+/// allow it are also read by asking them to reply with an OSC 52 sequence of
+/// their own.
+///
+/// The replies arrive as input on the tty, so nothing else should be reading
+/// from it, like a shell.
+#[derive(Debug)]
+pub struct Osc52Clipboard {
+    tty: PathBuf,
+    selection: Selection,
+    /// what was most recently copied to the terminal, which is what it's
+    /// assumed to contain if it doesn't answer queries
+    written: Mutex<ClipboardContent>,
+    answers: AtomicBool,
+    /// the contents that the terminal most recently answered with or was
+    /// copied, and when
+    latest: Mutex<Option<(Instant, ClipboardContent)>>,
+    /// shared with every other clipboard of the same tty
+    lock: Arc<Mutex<()>>,
+}
+
+impl Osc52Clipboard {
+    pub fn new(tty: impl Into<PathBuf>, selection: Selection, query: bool) -> MyResult<Self> {
+        let tty = tty.into();
+        if !nix::unistd::isatty(open(&tty)?.as_raw_fd())? {
+            return Err(MyError::NotATerminal(tty));
+        }
+        let lock = ttys::get_mut().entry(tty.clone()).or_default().clone();
+        Ok(Self {
+            tty,
+            lock,
+            selection,
+            written: Mutex::new(ClipboardContent::new()),
+            answers: AtomicBool::new(query),
+            latest: Mutex::new(None),
+        })
+    }
+
+    fn query(&self, tty: &mut File) -> MyResult<Option<Vec<u8>>> {
+        let fd = tty.as_raw_fd();
+        // the reply is read a byte at a time as soon as it arrives, without
+        // waiting for a newline or echoing it to the terminal
+        let original = termios::tcgetattr(fd)?;
+        let mut raw = original.clone();
+        raw.local_flags
+            .remove(LocalFlags::ICANON | LocalFlags::ECHO);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(fd, SetArg::TCSANOW, &raw)?;
+        let _restore = Restore(fd, original);
+
+        let query = format!("\x1b]52;{};?\x07", parameter(self.selection));
+        tty.write_all(query.as_bytes())?;
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        let mut response = vec![];
+        loop {
+            if let Some(data) = parse(&response, self.selection) {
+                let decoded = BASE64
+                    .decode(data)
+                    .map_err(|_| MyError::Osc52Response(self.tty.clone()))?;
+                return Ok(Some(decoded));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+            if remaining.is_zero() || poll(&mut fds, remaining.as_millis() as i32)? == 0 {
+                return Ok(None);
+            }
+            let mut buf = [0; 4096];
+            let n = tty.read(&mut buf)?;
+            response.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
+impl Clipboard for Osc52Clipboard {
+    fn display(&self) -> String {
+        format!("{PREFIX}{}", self.tty.display())
+    }
+
+    fn get(&self) -> MyResult<ClipboardContent> {
+        let written = self.written.lock()?.clone();
+        if !self.answers.load(Ordering::Relaxed) {
+            return Ok(written);
+        }
+        if let Some((at, latest)) = &*self.latest.lock()? {
+            if at.elapsed() < QUERY_INTERVAL {
+                return Ok(latest.clone());
+            }
+        }
+        let queried = {
+            let _lock = self.lock.lock()?;
+            self.query(&mut open(&self.tty)?)?
+        };
+        let Some(text) = queried else {
+            log::warning!(
+                "terminal {:?} does not answer OSC 52 queries, so it will only be copied to",
+                self.tty
+            );
+            self.answers.store(false, Ordering::Relaxed);
+            return Ok(written);
+        };
+        // terminals only have text, so they keep the other mime types of
+        // whatever was copied to them
+        let text = String::from_utf8_lossy(&text);
+        let content = if written.text().as_deref() == Some(&*text) {
+            written
+        } else if text.is_empty() {
+            ClipboardContent::new()
+        } else {
+            ClipboardContent::from_text(&text)
+        };
+        *self.latest.lock()? = Some((Instant::now(), content.clone()));
+        Ok(content)
+    }
+
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
+        // an image would clear the terminal's text, so it's left alone
+        let Some(text) = value.text().or_else(|| value.is_empty().then(String::new)) else {
+            log::debug!("not copying contents without text to {}", self.display());
+            return Ok(());
+        };
+        let sequence = format!(
+            "\x1b]52;{};{}\x07",
+            parameter(self.selection),
+            BASE64.encode(text)
+        );
+        let _lock = self.lock.lock()?;
+        open(&self.tty)?.write_all(sequence.as_bytes())?;
+        *self.written.lock()? = value.clone();
+        *self.latest.lock()? = Some((Instant::now(), value.clone()));
+        Ok(())
+    }
+}
+
+/// Puts the terminal settings back the way they were.
+struct Restore(i32, Termios);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Err(err) = termios::tcsetattr(self.0, SetArg::TCSANOW, &self.1) {
+            log::error!("failed to restore the terminal settings: {err}");
+        }
+    }
+}
+
+fn open(tty: &Path) -> MyResult<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(nix::libc::O_NOCTTY)
+        .open(tty)?)
+}
+
+/// How OSC 52 refers to a selection.
+fn parameter(selection: Selection) -> char {
+    match selection {
+        Selection::Clipboard => 'c',
+        Selection::Primary => 'p',
+    }
+}
+
+/// Finds the first complete OSC 52 sequence in `input` for `selection` and
+/// returns its base64 data. The sequence may be terminated by either BEL or
+/// ST.
+fn parse(mut input: &[u8], selection: Selection) -> Option<&[u8]> {
+    loop {
+        let start = input.windows(5).position(|w| w == b"\x1b]52;")? + 5;
+        let sequence = &input[start..];
+        let end = sequence
+            .iter()
+            .enumerate()
+            .find(|&(i, &b)| b == b'\x07' || sequence[i..].starts_with(b"\x1b\\"))?
+            .0;
+        let params = &sequence[..end];
+        let data = params.iter().position(|&b| b == b';')?;
+        if params[..data] == [parameter(selection) as u8] {
+            return Some(&params[data + 1..]);
+        }
+        input = &sequence[end..];
+    }
+}
+
+#[test]
+fn test() {
+    use std::os::unix::io::FromRawFd;
+
+    let read_sequence = |terminal: &mut File| {
+        let mut sequence = vec![];
+        let mut byte = [0];
+        while sequence.last() != Some(&b'\x07') {
+            terminal.read_exact(&mut byte).unwrap();
+            sequence.push(byte[0]);
+        }
+        sequence
+    };
+
+    let selection = Selection::Clipboard;
+    assert_eq!(
+        Some(&b"aGk="[..]),
+        parse(b"junk\x1b]52;c;aGk=\x07", selection)
+    );
+    assert_eq!(
+        Some(&b"aGk="[..]),
+        parse(b"\x1b]52;c;aGk=\x1b\\", selection)
+    );
+    assert_eq!(None, parse(b"\x1b]52;c;aGk=", selection));
+    // a reply for the primary selection is not the clipboard's
+    assert_eq!(None, parse(b"\x1b]52;p;aGk=\x07", selection));
+    assert_eq!(
+        Some(&b"aGk="[..]),
+        parse(b"\x1b]52;p;eW8=\x07\x1b]52;c;aGk=\x07", selection)
+    );
+
+    let pty = nix::pty::openpty(None, None).unwrap();
+    let tty = nix::unistd::ttyname(pty.slave).unwrap();
+    let mut terminal = unsafe { File::from_raw_fd(pty.master) };
+    let clipboard = Osc52Clipboard::new(&tty, Selection::Clipboard, true).unwrap();
+    assert_eq!(format!("osc52:{}", tty.display()), clipboard.display());
+    let primary = Osc52Clipboard::new(&tty, Selection::Primary, false).unwrap();
+    assert!(Arc::ptr_eq(&clipboard.lock, &primary.lock));
+
+    clipboard
+        .set(&ClipboardContent::from_text("hello"))
+        .unwrap();
+    assert_eq!(b"\x1b]52;c;aGVsbG8=\x07", &*read_sequence(&mut terminal));
+    // contents without text don't replace the terminal's
+    let mut image = ClipboardContent::new();
+    image.insert("image/png", vec![0x89, b'P']);
+    clipboard.set(&image).unwrap();
+    // it isn't queried right after it was copied to, or when queries are off
+    assert_eq!(Some("hello".to_string()), clipboard.get().unwrap().text());
+    assert!(primary.get().unwrap().is_empty());
+
+    *clipboard.latest.lock().unwrap() = None;
+    let responder = std::thread::spawn(move || {
+        assert_eq!(b"\x1b]52;c;?\x07", &*read_sequence(&mut terminal));
+        terminal.write_all(b"\x1b]52;c;d29ybGQ=\x1b\\").unwrap();
+        terminal
+    });
+    assert_eq!(Some("world".to_string()), clipboard.get().unwrap().text());
+    let _terminal = responder.join().unwrap();
+
+    // nothing answers this time, so it's assumed to have what was copied
+    *clipboard.latest.lock().unwrap() = None;
+    assert_eq!(Some("hello".to_string()), clipboard.get().unwrap().text());
+    assert!(!clipboard.answers.load(Ordering::Relaxed));
+}
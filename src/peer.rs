@@ -0,0 +1,573 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chrono::Local;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::clipboard::{Clipboard, Selection};
+use crate::config::{Config, PeersConfig, SecretPolicy};
+use crate::content::ClipboardContent;
+use crate::error::{MyError, MyResult};
+use crate::log;
+
+/// The display name of the clipboard that stands for every peer. It can be
+/// configured like any other display, for example to put it in a group.
+pub const DISPLAY: &str = "peers";
+
+/// Sent first by both sides, to recognize each other.
+const MAGIC: &[u8; 16] = b"clipboard-sync 1";
+const MIN_KEY_LEN: usize = 16;
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+/// Until a peer sends its hello, it may not have the key, so it only gets to
+/// send a small frame.
+const MAX_HELLO_LEN: usize = 4 * 1024;
+/// Connections that are accepted while this many others haven't sent their
+/// hello yet are dropped.
+const MAX_UNAUTHENTICATED: usize = 16;
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections that are quiet for longer than this are considered dead.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const READ_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Exchanges clipboard contents with clipboard-sync on other machines over
+/// tcp. Every message is encrypted and authenticated with keys that are
+/// derived from the pre-shared key for each connection.
+///
+/// Contents received from a peer are relayed to the other peers, so machines
+/// that aren't connected to each other directly still sync through the ones in
+/// between. Contents that are already known are never relayed, which stops
+/// them from going around in circles.
+pub struct Peers {
+    key: Vec<u8>,
+    send_secrets: bool,
+    state: Mutex<State>,
+    /// accepted connections that haven't sent their hello yet
+    unauthenticated: AtomicUsize,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    /// the most recent contents of each selection, from here or from a peer
+    latest: BTreeMap<Selection, ClipboardContent>,
+    /// the messages to send to each connected peer, keyed by connection id
+    connections: BTreeMap<u64, Sender<Message>>,
+    /// sync loops that are waiting for contents from peers
+    watchers: Vec<(Selection, Sender<String>)>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Message {
+    Hello {
+        name: String,
+    },
+    Content {
+        selection: Selection,
+        content: ClipboardContent,
+    },
+    Ping,
+}
+
+impl Peers {
+    /// Listens for peers and connects to them, as configured. None if no peers
+    /// are configured.
+    pub fn start(config: &Config) -> MyResult<Option<Arc<Self>>> {
+        if !config.peers.enabled() {
+            return Ok(None);
+        }
+        let key = load_key(&config.peers)?;
+        let listener = match &config.peers.listen {
+            Some(address) => Some(TcpListener::bind(address)?),
+            None => None,
+        };
+        let peers = Arc::new(Self {
+            key,
+            send_secrets: config.secret_policy == SecretPolicy::Sync,
+            state: Mutex::new(State::default()),
+            unauthenticated: AtomicUsize::new(0),
+        });
+        if let Some(listener) = listener {
+            log::info!("listening for peers on {}", listener.local_addr()?);
+            let peers = peers.clone();
+            thread::Builder::new()
+                .name("peer listener".to_string())
+                .spawn(move || peers.accept(listener))?;
+        }
+        for address in &config.peers.connect {
+            let (peers, address) = (peers.clone(), address.clone());
+            thread::Builder::new()
+                .name(format!("peer {address}"))
+                .spawn(move || peers.dial(&address))?;
+        }
+
+        Ok(Some(peers))
+    }
+
+    pub fn clipboard(self: &Arc<Self>, selection: Selection) -> PeerClipboard {
+        PeerClipboard {
+            peers: self.clone(),
+            selection,
+        }
+    }
+
+    fn accept(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warning!("failed to accept a peer connection: {err}");
+                    continue;
+                }
+            };
+            let address = stream
+                .peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_default();
+            if self.unauthenticated.fetch_add(1, Ordering::SeqCst) >= MAX_UNAUTHENTICATED {
+                self.unauthenticated.fetch_sub(1, Ordering::SeqCst);
+                log::warning!("too many peers are connecting at once, dropping {address}");
+                continue;
+            }
+            let peers = self.clone();
+            thread::spawn(move || {
+                let unauthenticated = Unauthenticated(&peers.unauthenticated);
+                match peers.run(stream, false, Some(unauthenticated)) {
+                    Ok(()) => log::info!("peer {address} disconnected"),
+                    Err(err) => log::warning!("peer {address} disconnected: {err}"),
+                }
+            });
+        }
+    }
+
+    /// Keeps a connection to the peer at `address`, reconnecting whenever it's
+    /// lost.
+    fn dial(self: Arc<Self>, address: &str) {
+        loop {
+            let result = TcpStream::connect(address)
+                .map_err(MyError::from)
+                .and_then(|stream| self.run(stream, true, None));
+            match result {
+                Ok(()) => log::info!("peer {address} disconnected"),
+                Err(err) => log::debug!("no connection to peer {address}: {err}"),
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    }
+
+    /// Exchanges messages with a peer until the connection is lost.
+    /// `initiator` is the side that connected. An accepted connection stops
+    /// counting as `unauthenticated` once the peer sent its hello.
+    fn run(
+        &self,
+        mut stream: TcpStream,
+        initiator: bool,
+        unauthenticated: Option<Unauthenticated>,
+    ) -> MyResult<()> {
+        let address = stream.peer_addr()?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let (mut sealer, mut opener) = handshake(&mut stream, &self.key, initiator)?;
+        let name = hostname();
+        write_message(&mut stream, &mut sealer, &Message::Hello { name })?;
+        let Message::Hello { name } = read_message(&mut stream, &mut opener, MAX_HELLO_LEN)? else {
+            return Err(MyError::PeerHandshake("expected a hello".to_string()));
+        };
+        drop(unauthenticated);
+        log::info!("connected to peer {name} at {address}");
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let (tx, rx) = mpsc::channel();
+        let id = {
+            let mut state = self.state.lock()?;
+            state.next_id += 1;
+            let id = state.next_id;
+            state.connections.insert(id, tx);
+            id
+        };
+        let mut writer = stream.try_clone()?;
+        thread::spawn(move || loop {
+            let message = match rx.recv_timeout(PING_INTERVAL) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => Message::Ping,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            if let Err(err) = write_message(&mut writer, &mut sealer, &message) {
+                log::debug!("failed to send to peer {name}: {err}");
+                drop(writer.shutdown(Shutdown::Both));
+                return;
+            }
+        });
+
+        let result = loop {
+            match read_message(&mut stream, &mut opener, MAX_FRAME_LEN) {
+                Ok(Message::Content { selection, content }) => {
+                    if let Err(err) = self.received(id, selection, content) {
+                        break Err(err);
+                    }
+                }
+                Ok(Message::Hello { .. } | Message::Ping) => (),
+                Err(err) => break Err(err),
+            }
+        };
+        self.state.lock()?.connections.remove(&id);
+        drop(stream.shutdown(Shutdown::Both));
+
+        match result {
+            Err(MyError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Stores contents that were sent by the peer with the connection `from`,
+    /// then relays them to the other peers and wakes up the sync loops.
+    fn received(&self, from: u64, selection: Selection, content: ClipboardContent) -> MyResult<()> {
+        let mut state = self.state.lock()?;
+        if state.latest.get(&selection) == Some(&content) {
+            return Ok(());
+        }
+        log::info!("received {selection} contents from a peer");
+        state.latest.insert(selection, content.clone());
+        if content.is_secret() && !self.send_secrets {
+            log::info!("not relaying a secret to other peers");
+        } else {
+            for (_, connection) in state.connections.iter().filter(|(id, _)| **id != from) {
+                drop(connection.send(Message::Content {
+                    selection,
+                    content: content.clone(),
+                }));
+            }
+        }
+        let mut watchers = vec![];
+        for (watched, notify) in state.watchers.drain(..) {
+            if watched != selection || notify.send(DISPLAY.to_string()).is_ok() {
+                watchers.push((watched, notify));
+            }
+        }
+        state.watchers = watchers;
+
+        Ok(())
+    }
+
+    /// Sends contents from this machine to every peer.
+    fn publish(&self, selection: Selection, content: &ClipboardContent) -> MyResult<()> {
+        let mut state = self.state.lock()?;
+        if state.latest.get(&selection) == Some(content) {
+            return Ok(());
+        }
+        state.latest.insert(selection, content.clone());
+        if content.is_secret() && !self.send_secrets {
+            log::info!("not sending a secret to peers");
+            return Ok(());
+        }
+        for connection in state.connections.values() {
+            drop(connection.send(Message::Content {
+                selection,
+                content: content.clone(),
+            }));
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts an accepted connection as unauthenticated until it's dropped.
+struct Unauthenticated<'a>(&'a AtomicUsize);
+
+impl Drop for Unauthenticated<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Stands for every peer in the sync loop. Setting it sends the contents to
+/// the peers, and it reports the contents that peers send as changes.
+pub struct PeerClipboard {
+    peers: Arc<Peers>,
+    selection: Selection,
+}
+
+impl std::fmt::Debug for PeerClipboard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerClipboard")
+            .field("selection", &self.selection)
+            .finish()
+    }
+}
+
+impl Clipboard for PeerClipboard {
+    fn display(&self) -> String {
+        DISPLAY.to_string()
+    }
+
+    fn get(&self) -> MyResult<ClipboardContent> {
+        let state = self.peers.state.lock()?;
+        Ok(state
+            .latest
+            .get(&self.selection)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
+        self.peers.publish(self.selection, value)
+    }
+
+    fn watch(&self, notify: Sender<String>) -> MyResult<bool> {
+        self.peers
+            .state
+            .lock()?
+            .watchers
+            .push((self.selection, notify));
+        Ok(true)
+    }
+}
+
+/// Checks that the key for peers can be used, if peers are configured.
+pub fn check(config: &Config) -> MyResult<()> {
+    if config.peers.enabled() {
+        load_key(&config.peers)?;
+    }
+    Ok(())
+}
+
+fn load_key(config: &PeersConfig) -> MyResult<Vec<u8>> {
+    let path = config.key_file.clone().unwrap_or_default();
+    let invalid = |reason: String| MyError::PeerKey {
+        path: path.clone(),
+        reason,
+    };
+    let mut key = std::fs::read(&path).map_err(|err| invalid(err.to_string()))?;
+    while key.last().is_some_and(u8::is_ascii_whitespace) {
+        key.pop();
+    }
+    if key.len() < MIN_KEY_LEN {
+        return Err(invalid(format!(
+            "it needs at least {MIN_KEY_LEN} bytes, but it has {}",
+            key.len()
+        )));
+    }
+    let mode = std::fs::metadata(&path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        log::warning!("other users can read the peer key file {path:?}");
+    }
+
+    Ok(key)
+}
+
+/// Both sides send a random nonce, then derive a key for each direction from
+/// the pre-shared key and both nonces. So every connection uses new keys, and
+/// a side that doesn't have the pre-shared key fails to decrypt the first
+/// message.
+fn handshake(stream: &mut TcpStream, key: &[u8], initiator: bool) -> MyResult<(Cipher, Cipher)> {
+    let mut ours = [0; 32];
+    getrandom::getrandom(&mut ours).map_err(|err| MyError::PeerHandshake(err.to_string()))?;
+    stream.write_all(MAGIC)?;
+    stream.write_all(&ours)?;
+    let mut magic = [0; MAGIC.len()];
+    stream.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(MyError::PeerHandshake(
+            "the other side is not a compatible clipboard-sync".to_string(),
+        ));
+    }
+    let mut theirs = [0; 32];
+    stream.read_exact(&mut theirs)?;
+
+    let (initiator_nonce, responder_nonce) = match initiator {
+        true => (ours, theirs),
+        false => (theirs, ours),
+    };
+    let hkdf = Hkdf::<Sha256>::new(Some(&[initiator_nonce, responder_nonce].concat()), key);
+    let mut initiator_key = [0; 32];
+    let mut responder_key = [0; 32];
+    hkdf.expand(b"clipboard-sync initiator", &mut initiator_key)
+        .expect("32 bytes is a valid hkdf output length");
+    hkdf.expand(b"clipboard-sync responder", &mut responder_key)
+        .expect("32 bytes is a valid hkdf output length");
+
+    Ok(match initiator {
+        true => (Cipher::new(&initiator_key), Cipher::new(&responder_key)),
+        false => (Cipher::new(&responder_key), Cipher::new(&initiator_key)),
+    })
+}
+
+/// Encrypts or decrypts the messages in one direction of a connection. Each
+/// message uses the next nonce, so messages can't be replayed or reordered.
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        self.aead
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .expect("encryption only fails for absurdly large messages")
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> MyResult<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.aead
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .map_err(|_| MyError::PeerAuth)
+    }
+}
+
+/// Each message is sent as its encrypted length, followed by the encrypted json.
+fn write_message(stream: &mut TcpStream, cipher: &mut Cipher, message: &Message) -> MyResult<()> {
+    let frame = cipher.seal(&serde_json::to_vec(message)?);
+    stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+    stream.write_all(&frame)?;
+
+    Ok(())
+}
+
+/// Frames longer than `max_len` are rejected before they're read.
+fn read_message(stream: &mut TcpStream, cipher: &mut Cipher, max_len: usize) -> MyResult<Message> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(MyError::PeerFrameTooBig(len));
+    }
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame)?;
+
+    Ok(serde_json::from_slice(&cipher.open(&frame)?)?)
+}
+
+fn hostname() -> String {
+    let mut buf = [0; 256];
+    nix::unistd::gethostname(&mut buf)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[test]
+fn test() {
+    let dir = std::env::temp_dir().join(format!("clipboard-sync-peer-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let key_file = |name: &str, key: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, key).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        Some(path)
+    };
+    let config = |peers: PeersConfig| Config {
+        peers,
+        ..Default::default()
+    };
+    let connected = |peers: &Peers, n: usize| {
+        for _ in 0..100 {
+            if peers.state.lock().unwrap().connections.len() == n {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    };
+
+    let shared = key_file("shared", "correct horse battery staple\n");
+    let free = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = free.local_addr().unwrap().to_string();
+    drop(free);
+    let one = Peers::start(&config(PeersConfig {
+        listen: Some(address.clone()),
+        key_file: shared.clone(),
+        ..Default::default()
+    }))
+    .unwrap()
+    .unwrap();
+    let two = Peers::start(&config(PeersConfig {
+        connect: vec![address.clone()],
+        key_file: shared,
+        ..Default::default()
+    }))
+    .unwrap()
+    .unwrap();
+    assert!(connected(&one, 1) && connected(&two, 1));
+
+    let (notify, changes) = mpsc::channel();
+    assert!(two.clipboard(Selection::Clipboard).watch(notify).unwrap());
+    let copied = ClipboardContent::from_text("copied on one");
+    one.clipboard(Selection::Clipboard).set(&copied).unwrap();
+    assert_eq!(
+        DISPLAY,
+        changes.recv_timeout(Duration::from_secs(5)).unwrap()
+    );
+    assert_eq!(copied, two.clipboard(Selection::Clipboard).get().unwrap());
+    assert!(two.clipboard(Selection::Primary).get().unwrap().is_empty());
+
+    let (notify, changes) = mpsc::channel();
+    one.clipboard(Selection::Clipboard).watch(notify).unwrap();
+    let copied = ClipboardContent::from_text("copied on two");
+    two.clipboard(Selection::Clipboard).set(&copied).unwrap();
+    changes.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(copied, one.clipboard(Selection::Clipboard).get().unwrap());
+
+    // a peer with a different key is never connected
+    Peers::start(&config(PeersConfig {
+        connect: vec![address.clone()],
+        key_file: key_file("other", "not the same key at all"),
+        ..Default::default()
+    }))
+    .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(connected(&one, 1));
+
+    // a big frame is dropped before the hello proves that it has the key
+    let mut stranger = TcpStream::connect(&address).unwrap();
+    stranger.write_all(MAGIC).unwrap();
+    stranger.write_all(&[0; 32]).unwrap();
+    stranger
+        .write_all(&(MAX_FRAME_LEN as u32).to_be_bytes())
+        .unwrap();
+    // it's closed right away, instead of waiting for the rest of the frame
+    stranger
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stranger.read_to_end(&mut vec![]).unwrap();
+    for _ in 0..100 {
+        if one.unauthenticated.load(Ordering::SeqCst) == 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(0, one.unauthenticated.load(Ordering::SeqCst));
+
+    let err = load_key(&PeersConfig {
+        key_file: key_file("short", "short"),
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert!(err.to_string().contains("at least"), "{err}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
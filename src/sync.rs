@@ -1,25 +1,80 @@
 use chrono::Local;
 use std::collections::HashSet;
-use std::{thread::sleep, time::Duration};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use std::{thread, time::Duration};
 use wayland_client::ConnectError;
-use wl_clipboard_rs::paste::Error as PasteError;
 
 use crate::clipboard::*;
-use crate::error::{MyError, MyResult, StandardizedError};
+use crate::clock::Clock;
+use crate::config::{Config, SecretPolicy};
+use crate::content::ClipboardContent;
+use crate::control::SelectionControl;
+use crate::convert::Conversions;
+use crate::error::{MyError, MyResult};
+use crate::hotplug::{DisplayEvent, Hotplug};
 use crate::log::{self, concise_numbers};
+use crate::osc52::{self, Osc52Clipboard};
+use crate::peer::{self, Peers};
+use crate::tmux::{self, TmuxClipboard};
 
-pub fn get_clipboards() -> MyResult<Vec<Box<dyn Clipboard>>> {
-    log::debug!("identifying unique clipboards...");
-    let mut clipboards = get_clipboards_spec(get_wayland);
+/// Finds the clipboards in `group`, and copies the same contents to all of
+/// them.
+pub fn get_clipboards(
+    selection: Selection,
+    group: &str,
+    config: &Config,
+    peers: Option<&Arc<Peers>>,
+) -> MyResult<Vec<Box<dyn Clipboard>>> {
+    log::debug!("identifying unique {selection} clipboards in group {group}...");
+    let mut clipboards = get_clipboards_spec(config.wayland_displays.iter(), |n| {
+        get_wayland(n, selection, group, config)
+    });
     // let x11_backend = X11Backend::new()?;
-    clipboards.extend(get_clipboards_spec(get_x11));
+    clipboards.extend(get_clipboards_spec(config.x11_displays.iter(), |n| {
+        get_x11(n, selection, group, config)
+    }));
 
-    let start = clipboards
+    let mut others: Vec<Box<dyn Clipboard>> = vec![];
+    if let Some(peers) = peers.filter(|_| belongs(peer::DISPLAY, group, config)) {
+        others.push(Box::new(peers.clipboard(selection)));
+    }
+    others.extend(get_terminals(selection, group, config));
+    others.extend(
+        tmux::sockets().into_iter().filter_map(|name| {
+            get_tmux(&format!("{}{name}", tmux::PREFIX), selection, group, config)
+        }),
+    );
+    let clipboards = unify(clipboards, others, selection, config)?;
+
+    log::info!("Using {selection} clipboards in group {group}: {clipboards:?}");
+
+    Ok(clipboards)
+}
+
+/// Removes the `clipboards` that share their contents with another one, then
+/// copies the contents of the first one that isn't empty to all of them and to
+/// the `others`, which can't be compared, like peers, terminals, and tmux
+/// servers.
+fn unify(
+    clipboards: Vec<Box<dyn Clipboard>>,
+    others: Vec<Box<dyn Clipboard>>,
+    selection: Selection,
+    config: &Config,
+) -> MyResult<Vec<Box<dyn Clipboard>>> {
+    let originals = clipboards
         .iter()
         .map(|c| c.get().unwrap_or_default())
-        .find(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    let (start_display, start) = clipboards
+        .iter()
+        .zip(&originals)
+        .find(|(c, content)| !content.is_empty() && config.display(&c.display()).role.sends())
+        .map(|(c, content)| (Some(c.display()), content.clone()))
         .unwrap_or_default();
-    log::sensitive!(log::info, "Clipboard contents at the start: '{start}'");
+    log::debug!("{selection} mime types at the start: {start:?}");
+    log::sensitive!(log::info, "{selection} contents at the start: '{start}'");
 
     let mut remove_me = HashSet::new();
     let len = clipboards.len();
@@ -35,46 +90,488 @@ pub fn get_clipboards() -> MyResult<Vec<Box<dyn Clipboard>>> {
         }
     }
 
-    let clipboards = clipboards
+    let (mut clipboards, originals): (Vec<Box<dyn Clipboard>>, Vec<_>) = clipboards
         .into_iter()
+        .zip(originals)
         .enumerate()
         .filter(|(i, _)| !remove_me.contains(i))
-        .map(|(_, c)| c)
-        .collect::<Vec<Box<dyn Clipboard>>>();
+        .map(|(_, pair)| pair)
+        .unzip();
 
     // let clipboards = dedupe(clipboards)?;
 
-    for c in clipboards.iter() {
-        c.set(&start)?;
+    let sync_start = !start.is_secret() || config.secret_policy == SecretPolicy::Sync;
+    if !sync_start {
+        log::info!("not syncing the {selection} contents at the start because they are secret");
+    }
+    // the comparisons overwrote every clipboard, so each one gets either the
+    // contents that are synced at the start, or its own original contents
+    for (c, original) in clipboards.iter().zip(originals) {
+        if sync_start && receives(start_display.as_deref(), &c.display(), config) {
+            c.set(&start)?;
+        } else {
+            c.set(&original)?;
+        }
+    }
+    // the others are added after the comparisons, since those would have
+    // sent the display names to them
+    for c in others {
+        if sync_start
+            && !start.is_empty()
+            && receives(start_display.as_deref(), &c.display(), config)
+        {
+            c.set(&start)?;
+        }
+        clipboards.push(c);
     }
-
-    log::info!("Using clipboards: {:?}", clipboards);
 
     Ok(clipboards)
 }
 
-pub fn keep_synced(clipboards: &Vec<Box<dyn Clipboard>>) -> MyResult<()> {
+/// Syncs the clipboards until an error occurs, or until `control` asks for a
+/// pause or for the clipboards to be rediscovered, in which case it returns Ok.
+/// Polling, retries and secret expiry are timed by `clock`.
+pub fn keep_synced(
+    clipboards: &mut Vec<Box<dyn Clipboard>>,
+    selection: Selection,
+    group: &str,
+    config: &Config,
+    control: &SelectionControl,
+    clock: &impl Clock,
+) -> MyResult<()> {
+    let (notify, changes) = mpsc::channel();
+    control.discovered(clipboards, notify.clone());
+    // displays whose watchers stopped, which are polled instead
+    let died = Arc::new(Mutex::new(HashSet::new()));
+    // displays that stopped responding, which are skipped until they respond
+    let mut degraded = HashSet::new();
+    if control.interrupted() {
+        return Ok(());
+    }
+    // started first, so displays that start while the others are read are
+    // noticed too
+    let hotplug = watch_displays(selection, group, config, &notify)
+        .map_err(|err| log::warning!("new displays will not be synced until restart: {err}"))
+        .ok();
+    // a group without any displays, like the default group when every display
+    // is in a named group, waits for displays to appear
     if clipboards.is_empty() {
-        return Err(MyError::NoClipboards);
+        log::info!("no {selection} clipboards in group {group} yet, waiting for displays to start");
+    }
+    let mut polled = clipboards
+        .iter()
+        .map(|c| watch(&**c, config, &notify, &died))
+        .collect::<Vec<_>>();
+    let mut seen = vec![];
+    for c in clipboards.iter() {
+        let content = tolerate(c.get(), &c.display(), &mut degraded, control)?;
+        seen.push(content.unwrap_or_default());
     }
+    // the contents that were most recently synced, for new displays
+    let mut current = seen
+        .iter()
+        .find(|content| !content.is_empty())
+        .cloned()
+        .unwrap_or_default();
+    control.current(&current);
+    // images are converted to the other common formats in the background
+    let conversions = Conversions::default();
+    conversions.start(None, &current, &notify);
+    // when a secret was synced, the time to clear it
+    let mut expiry = None;
     loop {
-        sleep(Duration::from_millis(100));
-        let new_value = await_change(clipboards)?;
-        for c in clipboards {
-            c.set(&new_value)?;
+        control.update_displays(clipboards, &degraded);
+        clock.sleep(config.settle_delay());
+        let event = await_change(
+            clipboards,
+            &mut seen,
+            &mut degraded,
+            &changes,
+            &mut polled,
+            &died,
+            config.poll_interval(),
+            expiry,
+            &conversions,
+            control,
+            hotplug.as_ref(),
+            clock,
+        )?;
+        let (display, new_value) = match event {
+            Event::Changed(i, new_value) => (clipboards[i].display(), new_value),
+            Event::Expired => {
+                log::info!("clearing an expired secret from every clipboard");
+                // secrets are cleared everywhere, even from displays that
+                // only send, since they were copied there in the first place
+                for (c, seen) in clipboards.iter().zip(seen.iter_mut()) {
+                    let display = c.display();
+                    if !seen.is_secret() || degraded.contains(&display) {
+                        continue;
+                    }
+                    let empty = ClipboardContent::new();
+                    // one that stops responding still has the secret, so it's
+                    // cleared when it's back
+                    if tolerate(c.set(&empty), &display, &mut degraded, control)?.is_some() {
+                        let cleared = tolerate(c.get(), &display, &mut degraded, control)?;
+                        *seen = cleared.unwrap_or_default();
+                    }
+                }
+                current = ClipboardContent::new();
+                control.current(&current);
+                expiry = None;
+                continue;
+            }
+            Event::Recovered(i, content) => {
+                let display = clipboards[i].display();
+                log::info!(event = "recovered", display = display; "{display} is responding again");
+                // something was copied in it while it was degraded, which is
+                // newer than what the others have
+                if content != seen[i] && content != current && config.display(&display).role.sends()
+                {
+                    log::info!(
+                        event = "changed", display = display;
+                        "clipboard updated from display {display} while it was degraded"
+                    );
+                    seen[i] = content.clone();
+                    (display, content)
+                } else {
+                    seen[i] = content;
+                    if config.display(&display).role.receives() && seen[i] != current {
+                        let c = &clipboards[i];
+                        if tolerate(c.set(&current), &display, &mut degraded, control)?.is_some() {
+                            let updated = tolerate(c.get(), &display, &mut degraded, control)?;
+                            seen[i] = updated.unwrap_or_else(|| current.clone());
+                        }
+                    }
+                    continue;
+                }
+            }
+            Event::Converted => {
+                let Some((origin, converted)) = conversions.take(&current) else {
+                    continue;
+                };
+                log::debug!("offering the clipboard image in the formats it was converted to");
+                sync_from(
+                    origin.as_deref(),
+                    clipboards,
+                    &mut seen,
+                    &mut degraded,
+                    &converted,
+                    config,
+                    control,
+                )?;
+                control.current(&converted);
+                current = converted;
+                continue;
+            }
+            Event::Interrupted => {
+                for event in hotplug.iter().flat_map(|h| h.take()) {
+                    match event {
+                        DisplayEvent::Added(name) => {
+                            let first = clipboards.is_empty();
+                            // a display that just started may not be ready yet,
+                            // which doesn't stop the others from syncing
+                            let added =
+                                get_display(&name, selection, group, config).and_then(|added| {
+                                    match added {
+                                        Some(added) => add_clipboard(
+                                            clipboards, &seen, added, &current, config,
+                                        ),
+                                        None => Ok(None),
+                                    }
+                                });
+                            let i = match added {
+                                Ok(Some(i)) => i,
+                                Ok(None) => continue,
+                                Err(err) => {
+                                    log::warning!(
+                                        event = "display_failed", display = name, error = err;
+                                        "not syncing new display {name}: {err}"
+                                    );
+                                    continue;
+                                }
+                            };
+                            log::info!(
+                                event = "display_added", display = name, group = group;
+                                "syncing new display {name} in group {group}"
+                            );
+                            polled.insert(i, watch(&*clipboards[i], config, &notify, &died));
+                            // one that can't be read is skipped until it can
+                            match clipboards[i].get() {
+                                Ok(content) => seen.insert(i, content),
+                                Err(err) => {
+                                    seen.insert(i, ClipboardContent::new());
+                                    degrade(&name, &err, &mut degraded, control);
+                                    continue;
+                                }
+                            }
+                            // nothing else had contents to give it
+                            if first {
+                                current = seen[i].clone();
+                                control.current(&current);
+                            }
+                        }
+                        DisplayEvent::Removed(name) => {
+                            while let Some(i) = clipboards.iter().position(|c| c.display() == name)
+                            {
+                                log::info!(
+                                    event = "display_removed", display = name, group = group;
+                                    "display {name} is gone, no longer syncing it"
+                                );
+                                clipboards.remove(i);
+                                polled.remove(i);
+                                seen.remove(i);
+                                degraded.remove(&name);
+                            }
+                        }
+                    }
+                }
+                if let Some(restored) = control.take_restore() {
+                    log::info!("copying restored contents to every clipboard");
+                    sync_from(
+                        None,
+                        clipboards,
+                        &mut seen,
+                        &mut degraded,
+                        &restored,
+                        config,
+                        control,
+                    )?;
+                    control.current(&restored);
+                    current = restored;
+                    expiry = None;
+                } else if control.interrupted() {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        expiry = None;
+        if new_value.is_secret() {
+            match config.secret_policy {
+                SecretPolicy::Skip => {
+                    log::info!(
+                        event = "secret_skipped", display = display;
+                        "not syncing a secret from display {display}"
+                    );
+                    continue;
+                }
+                SecretPolicy::Expire => expiry = Some(clock.now() + config.secret_expiry()),
+                SecretPolicy::Sync => (),
+            }
+        }
+        sync_from(
+            Some(&display),
+            clipboards,
+            &mut seen,
+            &mut degraded,
+            &new_value,
+            config,
+            control,
+        )?;
+        control.synced(&display, &new_value);
+        conversions.start(Some(&display), &new_value, &notify);
+        current = new_value;
+    }
+}
+
+/// Starts watching the clipboard for changes, and returns whether it needs to
+/// be polled instead. Changes in sink-only displays are ignored, so they are
+/// neither watched nor polled.
+///
+/// A watcher that stops, for example because it lost its connection, adds the
+/// display to `died` and wakes up the sync loop, so the display can be polled
+/// instead.
+fn watch(
+    c: &dyn Clipboard,
+    config: &Config,
+    notify: &Sender<String>,
+    died: &Arc<Mutex<HashSet<String>>>,
+) -> bool {
+    if !config.display(&c.display()).role.sends() {
+        log::debug!(
+            "ignoring changes in {} because it is sink-only",
+            c.display()
+        );
+        return false;
+    }
+    if config.display(&c.display()).poll {
+        log::debug!("polling {} for changes, as configured", c.display());
+        return true;
+    }
+    // each watcher gets its own channel, which is disconnected when it stops
+    let (watcher, changes) = mpsc::channel();
+    match c.watch(watcher) {
+        Ok(true) => {
+            log::debug!("watching {} for changes", c.display());
+            let (display, notify, died) = (c.display(), notify.clone(), died.clone());
+            thread::spawn(move || {
+                for changed in changes {
+                    if notify.send(changed).is_err() {
+                        return;
+                    }
+                }
+                died.lock().unwrap().insert(display.clone());
+                drop(notify.send(display));
+            });
+            false
+        }
+        Ok(false) => true,
+        Err(err) => {
+            log::warning!("falling back to polling {}: {err}", c.display());
+            true
+        }
+    }
+}
+
+/// Notices displays in the group that start or stop while syncing.
+fn watch_displays(
+    selection: Selection,
+    group: &str,
+    config: &Config,
+    notify: &Sender<String>,
+) -> MyResult<Hotplug> {
+    let (accept_group, accept_config) = (group.to_string(), config.clone());
+    let (probe_group, probe_config) = (group.to_string(), config.clone());
+    Hotplug::watch(
+        move |name| {
+            (in_range(name, &accept_config) || name.starts_with(tmux::PREFIX))
+                && belongs(name, &accept_group, &accept_config)
+        },
+        move |name| {
+            matches!(
+                get_display(name, selection, &probe_group, &probe_config),
+                Ok(Some(_))
+            )
+        },
+        notify.clone(),
+    )
+}
+
+/// Adds a clipboard for a new display, unless it's the same as a clipboard
+/// that's already synced, then copies the `current` contents to it. Returns
+/// the index of the added clipboard.
+fn add_clipboard(
+    clipboards: &mut Vec<Box<dyn Clipboard>>,
+    seen: &[ClipboardContent],
+    added: Box<dyn Clipboard>,
+    current: &ClipboardContent,
+    config: &Config,
+) -> MyResult<Option<usize>> {
+    if clipboards.iter().any(|c| c.display() == added.display()) {
+        return Ok(None);
+    }
+    let original = added.get()?;
+    for (c, seen) in clipboards.iter().zip(seen) {
+        if !comparable(&c.display()) {
+            continue;
+        }
+        if are_same(&*added, &**c)? {
+            log::debug!("{added:?} is the same as {c:?}, so it's already synced");
+            c.set(seen)?;
+            return Ok(None);
+        }
+    }
+    // the first display keeps its own contents, since there's nothing to sync yet
+    if config.display(&added.display()).role.receives() && !clipboards.is_empty() {
+        added.set(current)?;
+    } else {
+        added.set(&original)?;
+    }
+    clipboards.push(added);
+
+    Ok(Some(clipboards.len() - 1))
+}
+
+/// Copies `content` from the `from` display to every clipboard that the
+/// config allows, then records what each one holds afterwards, which may
+/// include extra mime types that the clipboard added. When `from` is None, the
+/// content is copied to every clipboard that receives anything. It's never
+/// copied back to `from`, which would take the selection from the app that
+/// owns it, and clear what's highlighted in the primary selection.
+fn sync_from(
+    from: Option<&str>,
+    clipboards: &[Box<dyn Clipboard>],
+    seen: &mut [ClipboardContent],
+    degraded: &mut HashSet<String>,
+    content: &ClipboardContent,
+    config: &Config,
+    control: &SelectionControl,
+) -> MyResult<()> {
+    for (c, seen) in clipboards.iter().zip(seen.iter_mut()) {
+        let display = c.display();
+        if from == Some(display.as_str()) {
+            continue;
+        }
+        if receives(from, &display, config) && !degraded.contains(&display) {
+            log::debug!(
+                event = "synced", display = display, origin = from;
+                "copying the contents to {display}"
+            );
+            // a display that stops responding keeps what was last seen in it,
+            // so it's known whether it changed by the time it's back
+            if tolerate(c.set(content), &display, degraded, control)?.is_none() {
+                continue;
+            }
+            let updated = tolerate(c.get(), &display, degraded, control)?;
+            *seen = updated.unwrap_or_else(|| content.clone());
+        }
+    }
+
+    Ok(())
+}
+
+/// Marks the display as degraded if the call timed out, so the other displays
+/// keep syncing without it, and tells `control` about it. Other errors are
+/// returned.
+fn tolerate<T>(
+    result: MyResult<T>,
+    display: &str,
+    degraded: &mut HashSet<String>,
+    control: &SelectionControl,
+) -> MyResult<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.is_timeout() => {
+            degrade(display, &err, degraded, control);
+            Ok(None)
         }
+        Err(err) => Err(err),
+    }
+}
+
+/// Skips `display`, which failed with `err`, until it responds again.
+fn degrade(
+    display: &str,
+    err: &MyError,
+    degraded: &mut HashSet<String>,
+    control: &SelectionControl,
+) {
+    if degraded.insert(display.to_string()) {
+        control.stopped_responding(display);
+        log::warning!(
+            event = "degraded", display = display, error = err;
+            "skipping {display} until it responds again: {err}"
+        );
+    }
+}
+
+/// Whether content from the `from` display may be copied to the `to` display.
+fn receives(from: Option<&str>, to: &str, config: &Config) -> bool {
+    match from {
+        Some(from) => config.may_sync(from, to),
+        None => config.display(to).role.receives(),
     }
 }
 
 fn are_same(one: &dyn Clipboard, two: &dyn Clipboard) -> MyResult<bool> {
-    let d1 = &one.display();
-    let d2 = &two.display();
-    one.set(d1)?;
-    if d1 != &two.get()? {
+    let d1 = ClipboardContent::from_text(&one.display());
+    let d2 = ClipboardContent::from_text(&two.display());
+    one.set(&d1)?;
+    if d1.text() != two.get()?.text() {
         return Ok(false);
     }
-    two.set(d2)?;
-    if d2 != &one.get()? {
+    two.set(&d2)?;
+    if d2.text() != one.get()?.text() {
         return Ok(false);
     }
 
@@ -151,12 +648,13 @@ fn are_same(one: &dyn Clipboard, two: &dyn Clipboard) -> MyResult<bool> {
 // }
 
 fn get_clipboards_spec<F: Fn(u8) -> MyResult<Option<Box<dyn Clipboard>>>>(
+    displays: impl Iterator<Item = u8>,
     getter: F,
 ) -> Vec<Box<dyn Clipboard>> {
     let mut clipboards: Vec<Box<dyn Clipboard>> = Vec::new();
     let mut xcb_conn_err = None;
     let mut xcb_conn_failed_clipboards = vec![];
-    for i in 0..u8::MAX {
+    for i in displays {
         let result = getter(i);
         match result {
             Ok(option) => {
@@ -165,13 +663,10 @@ fn get_clipboards_spec<F: Fn(u8) -> MyResult<Option<Box<dyn Clipboard>>>>(
                     clipboards.push(clipboard);
                 }
             }
-            Err(MyError::TerminalClipboard(StandardizedError {
-                inner,
-                stdio: None,
-            })) if format!("{inner}") == "clipboard error: X11 clipboard error : XCB connection error: Connection" => {
+            Err(MyError::X11Connect(inner @ xcb::ConnError::Connection)) => {
                 xcb_conn_failed_clipboards.push(i);
                 xcb_conn_err = Some(inner);
-            },
+            }
             Err(err) => log::error!(
                 "unexpected error while attempting to setup clipboard {}: {}",
                 i,
@@ -191,50 +686,589 @@ Details: '{err}' for x11 displays: {displays}",
     clipboards
 }
 
-fn get_wayland(n: u8) -> MyResult<Option<Box<dyn Clipboard>>> {
+/// The terminals in `group`, which are synced with OSC 52 escape sequences.
+fn get_terminals(selection: Selection, group: &str, config: &Config) -> Vec<Box<dyn Clipboard>> {
+    let mut terminals: Vec<Box<dyn Clipboard>> = vec![];
+    for tty in &config.terminals {
+        let name = format!("{}{}", osc52::PREFIX, tty.display());
+        if !belongs(&name, group, config) {
+            continue;
+        }
+        match Osc52Clipboard::new(tty, selection, config.query_terminals) {
+            Ok(c) => terminals.push(Box::new(Deadline::new(c, config.operation_timeout()))),
+            Err(err) => {
+                log::error!(display = name, error = err; "not syncing terminal {tty:?}: {err}")
+            }
+        }
+    }
+
+    terminals
+}
+
+/// Whether are_same can tell if the display shares a clipboard with another
+/// one. Peers, terminals, and tmux servers never do, and comparing them would
+/// copy the display names to them.
+fn comparable(display: &str) -> bool {
+    display != peer::DISPLAY
+        && !display.starts_with(osc52::PREFIX)
+        && !display.starts_with(tmux::PREFIX)
+}
+
+/// The tmux server for a display name like "tmux-default", if it's running
+/// and it should be synced as part of `group`. tmux has no primary selection.
+fn get_tmux(
+    name: &str,
+    selection: Selection,
+    group: &str,
+    config: &Config,
+) -> Option<Box<dyn Clipboard>> {
+    if selection != Selection::Clipboard || !belongs(name, group, config) {
+        return None;
+    }
+    let clipboard = TmuxClipboard::new(name.strip_prefix(tmux::PREFIX)?)?;
+    Some(Box::new(Deadline::new(
+        clipboard,
+        config.operation_timeout(),
+    )))
+}
+
+fn get_wayland(
+    n: u8,
+    selection: Selection,
+    group: &str,
+    config: &Config,
+) -> MyResult<Option<Box<dyn Clipboard>>> {
     let wl_display = format!("wayland-{}", n);
-    let clipboard = WlrClipboard {
-        display: wl_display.clone(),
-    };
+    if !belongs(&wl_display, group, config) {
+        return Ok(None);
+    }
+    let clipboard = Deadline::new(
+        WlrClipboard {
+            display: wl_display.clone(),
+            selection,
+        },
+        config.operation_timeout(),
+    );
     let attempt = clipboard.get();
-    if let Err(MyError::WlcrsPaste(PasteError::WaylandConnection(
-        ConnectError::NoCompositorListening,
-    ))) = attempt
-    {
+    if let Err(MyError::WaylandConnect(ConnectError::NoCompositorListening)) = attempt {
         return Ok(None);
     }
-    if let Err(MyError::WlcrsPaste(PasteError::MissingProtocol {
+    if let Err(MyError::MissingWaylandProtocol {
         name: "zwlr_data_control_manager_v1",
         version: 1,
-    })) = attempt
+    }) = attempt
     {
         log::warning!("{wl_display} does not support zwlr_data_control_manager_v1. If you are running gnome in wayland, that's OK because it provides an x11 clipboard, which will be used instead.");
         return Ok(None);
     }
+    if let Err(MyError::MissingWaylandProtocol {
+        name: "zwlr_data_control_manager_v1",
+        version: 2,
+    }) = attempt
+    {
+        log::warning!("{wl_display} does not support version 2 of zwlr_data_control_manager_v1, so its primary selection will not be synced.");
+        return Ok(None);
+    }
     attempt?;
 
     Ok(Some(Box::new(clipboard)))
 }
 
-fn get_x11(n: u8) -> MyResult<Option<Box<dyn Clipboard>>> {
+fn get_x11(
+    n: u8,
+    selection: Selection,
+    group: &str,
+    config: &Config,
+) -> MyResult<Option<Box<dyn Clipboard>>> {
     let display = format!(":{}", n);
-    let clipboard = X11Clipboard::new(display)?;
-    clipboard.get()?;
+    if !belongs(&display, group, config) {
+        return Ok(None);
+    }
+    let timeout = config.operation_timeout();
+    let clipboard = with_deadline(&display.clone(), timeout, move || {
+        let clipboard = X11Clipboard::new(display, selection)?;
+        clipboard.get()?;
+        Ok(clipboard)
+    })?;
 
-    Ok(Some(Box::new(clipboard)))
+    Ok(Some(Box::new(Deadline::new(clipboard, timeout))))
+}
+
+/// Connects to a display by name, like "wayland-1", ":0", or "tmux-default", if
+/// it's in one of the configured ranges and it should be synced as part of
+/// `group`.
+fn get_display(
+    name: &str,
+    selection: Selection,
+    group: &str,
+    config: &Config,
+) -> MyResult<Option<Box<dyn Clipboard>>> {
+    if name.starts_with(tmux::PREFIX) {
+        return Ok(get_tmux(name, selection, group, config));
+    }
+    if !in_range(name, config) {
+        return Ok(None);
+    }
+    match (display_number(name, "wayland-"), display_number(name, ":")) {
+        (Some(n), _) => get_wayland(n, selection, group, config),
+        (_, Some(n)) => get_x11(n, selection, group, config),
+        _ => Ok(None),
+    }
+}
+
+/// Whether the display is in the configured wayland or x11 range.
+fn in_range(name: &str, config: &Config) -> bool {
+    display_number(name, "wayland-").is_some_and(|n| config.wayland_displays.contains(n))
+        || display_number(name, ":").is_some_and(|n| config.x11_displays.contains(n))
+}
+
+fn display_number(name: &str, prefix: &str) -> Option<u8> {
+    name.strip_prefix(prefix)?.parse().ok()
+}
+
+/// Whether the display should be synced as part of `group`.
+fn belongs(display: &str, group: &str, config: &Config) -> bool {
+    !config.display(display).ignore && config.group_of(display) == Some(group)
+}
+
+/// How often to check whether degraded displays respond again. While a call
+/// that timed out is still stuck, this fails immediately, so it's cheap.
+const DEGRADED_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the sync loop shows that it's still running while it waits for
+/// changes, so a watchdog can tell when it's stuck.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why await_change stopped waiting.
+enum Event {
+    /// the clipboard at this index has new contents
+    Changed(usize, ClipboardContent),
+    /// a secret that was synced needs to be cleared
+    Expired,
+    /// the degraded clipboard at this index responded again, with these contents
+    Recovered(usize, ClipboardContent),
+    /// `control` needs the sync loop's attention
+    Interrupted,
+    /// an image was converted to other formats
+    Converted,
 }
 
-fn await_change(clipboards: &Vec<Box<dyn Clipboard>>) -> MyResult<String> {
-    let start = clipboards[0].get()?;
+/// Blocks until any clipboard's contents differ from what was `seen` in it
+/// the last time it was read, then updates `seen`. Clipboards that report
+/// their own changes are only read when they send a notification, while the
+/// `polled` clipboards are read every `poll_interval`, and so are the ones
+/// whose watchers `died`. New or removed displays interrupt the wait, like
+/// `control` and finished `conversions` do. Clipboards that time out are added
+/// to `degraded`, and they're retried every `DEGRADED_RETRY_INTERVAL`. It wakes
+/// up every `HEARTBEAT_INTERVAL` to tell `control` that it's still running.
+#[allow(clippy::too_many_arguments)]
+fn await_change(
+    clipboards: &[Box<dyn Clipboard>],
+    seen: &mut [ClipboardContent],
+    degraded: &mut HashSet<String>,
+    changes: &Receiver<String>,
+    polled: &mut [bool],
+    died: &Mutex<HashSet<String>>,
+    poll_interval: Duration,
+    expiry: Option<SystemTime>,
+    conversions: &Conversions,
+    control: &SelectionControl,
+    hotplug: Option<&Hotplug>,
+    clock: &impl Clock,
+) -> MyResult<Event> {
+    let mut next_poll = clock.now();
+    let mut next_retry = clock.now() + DEGRADED_RETRY_INTERVAL;
     loop {
-        for c in clipboards {
-            let new = c.get()?;
-            if new != start {
-                log::info!("clipboard updated from display {}", c.display());
-                log::sensitive!(log::info, "clipboard contents: '{}'", new);
-                return Ok(new);
+        control.alive();
+        for display in died.lock()?.drain() {
+            for i in changed(clipboards, &display) {
+                if !polled[i] {
+                    log::warning!(
+                        event = "watcher_died", display = display;
+                        "stopped watching {display} for changes, polling it instead"
+                    );
+                    polled[i] = true;
+                }
+            }
+        }
+        let any_polled = polled.iter().any(|&p| p);
+        if control.interrupted() || hotplug.is_some_and(|h| h.pending()) {
+            return Ok(Event::Interrupted);
+        }
+        if expiry.is_some_and(|expiry| expiry <= clock.now()) {
+            return Ok(Event::Expired);
+        }
+        if conversions.ready() {
+            return Ok(Event::Converted);
+        }
+        if !degraded.is_empty() && next_retry <= clock.now() {
+            next_retry = clock.now() + DEGRADED_RETRY_INTERVAL;
+            for (i, c) in clipboards.iter().enumerate() {
+                let display = c.display();
+                if !degraded.contains(&display) {
+                    continue;
+                }
+                match c.get() {
+                    Ok(content) => {
+                        degraded.remove(&display);
+                        return Ok(Event::Recovered(i, content));
+                    }
+                    Err(err) if err.is_timeout() => {
+                        log::debug!("{display} is still degraded: {err}")
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        let deadline = [
+            any_polled.then_some(next_poll),
+            expiry,
+            (!degraded.is_empty()).then_some(next_retry),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(clock.now() + HEARTBEAT_INTERVAL, SystemTime::min);
+        let timeout = deadline.duration_since(clock.now()).unwrap_or_default();
+        let candidates = match clock.recv_timeout(changes, timeout) {
+            Ok(display) => changed(clipboards, &display),
+            Err(RecvTimeoutError::Timeout) if any_polled && next_poll <= clock.now() => {
+                next_poll = clock.now() + poll_interval;
+                (0..polled.len()).filter(|&i| polled[i]).collect()
             }
+            Err(RecvTimeoutError::Timeout) => vec![],
+            Err(RecvTimeoutError::Disconnected) => return Err(MyError::WatcherDied),
+        };
+        for i in candidates {
+            let c = &clipboards[i];
+            let display = c.display();
+            if degraded.contains(&display) {
+                continue;
+            }
+            let Some(new) = tolerate(c.get(), &display, degraded, control)? else {
+                continue;
+            };
+            if new != seen[i] {
+                seen[i] = new.clone();
+                log::info!(
+                    event = "changed", display = display;
+                    "clipboard updated from display {display}"
+                );
+                log::debug!("new clipboard mime types: {new:?}");
+                log::sensitive!(log::info, display = display, content = new; "clipboard contents: '{}'", new);
+                return Ok(Event::Changed(i, new));
+            }
+        }
+    }
+}
+
+/// The indexes of the clipboards that belong to a display that sent a change
+/// notification.
+fn changed(clipboards: &[Box<dyn Clipboard>], display: &str) -> Vec<usize> {
+    clipboards
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.display() == display)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Syncs mock clipboards in the default group on a thread of its own, timed
+/// by a clock that only moves when the test advances it.
+#[cfg(test)]
+struct TestSync {
+    control: Arc<crate::control::Control>,
+    clock: Arc<crate::mock::MockClock>,
+    thread: thread::JoinHandle<MyResult<()>>,
+}
+
+#[cfg(test)]
+impl TestSync {
+    fn start(mut clipboards: Vec<Box<dyn Clipboard>>, config: Config) -> Self {
+        let control = Arc::new(crate::control::Control::new(None));
+        let clock = Arc::new(crate::mock::MockClock::new());
+        let selection = control.selection("default", Selection::Clipboard);
+        let thread = {
+            let clock = clock.clone();
+            thread::spawn(move || {
+                keep_synced(
+                    &mut clipboards,
+                    Selection::Clipboard,
+                    "default",
+                    &config,
+                    &selection,
+                    &*clock,
+                )
+            })
+        };
+        let sync = Self {
+            control,
+            clock,
+            thread,
+        };
+        // the contents are known once the clipboards have been read
+        sync.wait_until("the clipboards are read", || {
+            sync.control
+                .content("default", Selection::Clipboard)
+                .is_some()
+        });
+        sync
+    }
+
+    /// Waits for `done` without moving the clock.
+    fn wait_until(&self, what: &str, done: impl Fn() -> bool) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "timed out waiting until {what}"
+            );
+            thread::sleep(Duration::from_millis(1));
         }
-        sleep(Duration::from_millis(200));
     }
+
+    /// Moves the clock a poll interval at a time until `done`.
+    fn eventually(&self, what: &str, done: impl Fn() -> bool) {
+        self.wait_until(what, || {
+            self.clock
+                .advance(Duration::from_millis(TEST_POLL_INTERVAL_MS));
+            done()
+        });
+    }
+
+    fn stop(self) {
+        self.control.handle(crate::control::Request::Pause);
+        self.thread.join().unwrap().unwrap();
+    }
+}
+
+#[cfg(test)]
+const TEST_POLL_INTERVAL_MS: u64 = 10;
+
+#[cfg(test)]
+fn test_config() -> Config {
+    Config {
+        poll_interval_ms: TEST_POLL_INTERVAL_MS,
+        settle_delay_ms: 0,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn clipboards_are_unified() {
+    use crate::mock::MockClipboard;
+
+    let text = ClipboardContent::from_text;
+    // xwayland shares its clipboard with the compositor that runs it
+    let wayland = MockClipboard::new("wayland-1").watchable();
+    let xwayland = wayland.alias(":0");
+    let x11 = MockClipboard::new(":1");
+    let peer = MockClipboard::new(peer::DISPLAY);
+    assert!(are_same(&wayland, &xwayland).unwrap());
+    assert!(!are_same(&wayland, &x11).unwrap());
+    // both directions are checked, so a display that happens to have the
+    // same contents once isn't mistaken for an alias
+    x11.script_read(text("wayland-1"));
+    assert!(!are_same(&wayland, &x11).unwrap());
+
+    // the first display that isn't empty is copied to the others
+    wayland.copy(ClipboardContent::new());
+    x11.copy(text("start"));
+    let clipboards: Vec<Box<dyn Clipboard>> = vec![
+        Box::new(wayland.clone()),
+        Box::new(xwayland.clone()),
+        Box::new(x11.clone()),
+    ];
+    let others: Vec<Box<dyn Clipboard>> = vec![Box::new(peer.clone())];
+    let clipboards = unify(clipboards, others, Selection::Clipboard, &test_config()).unwrap();
+    let displays = clipboards.iter().map(|c| c.display()).collect::<Vec<_>>();
+    assert_eq!(vec!["wayland-1", ":1", "peers"], displays);
+    assert_eq!(text("start"), wayland.contents());
+    assert_eq!(text("start"), x11.contents());
+    // the peer was never compared with the others
+    assert_eq!(vec![text("start")], peer.writes());
+}
+
+#[test]
+fn changes_are_synced() {
+    use crate::mock::MockClipboard;
+
+    let text = ClipboardContent::from_text;
+    let wayland = MockClipboard::new("wayland-1").watchable();
+    let x11 = MockClipboard::new(":1");
+    let peer = MockClipboard::new(peer::DISPLAY);
+    let sync = TestSync::start(
+        vec![
+            Box::new(wayland.clone()),
+            Box::new(x11.clone()),
+            Box::new(peer.clone()),
+        ],
+        test_config(),
+    );
+
+    // x11 is polled, and wayland reports its own changes
+    x11.copy(text("from x11"));
+    sync.eventually("x11 is synced", || wayland.contents() == text("from x11"));
+    sync.wait_until("the peer has it", || peer.contents() == text("from x11"));
+    // the change isn't copied back to where it came from
+    assert!(!x11.writes().contains(&text("from x11")));
+    wayland.copy(text("from wayland"));
+    sync.wait_until("wayland is synced", || {
+        x11.contents() == text("from wayland")
+    });
+
+    // a display whose watcher stops is polled instead
+    wayland.stop_watching();
+    wayland.copy(text("unwatched"));
+    sync.eventually("wayland is polled", || x11.contents() == text("unwatched"));
+    sync.stop();
+}
+
+#[test]
+fn displays_that_stop_responding_catch_up() {
+    use crate::mock::MockClipboard;
+
+    let text = ClipboardContent::from_text;
+    let wayland = MockClipboard::new("wayland-1").watchable();
+    let x11 = MockClipboard::new(":1");
+    let sync = TestSync::start(
+        vec![Box::new(wayland.clone()), Box::new(x11.clone())],
+        test_config(),
+    );
+    let errors = || {
+        sync.control
+            .display_errors(Duration::from_secs(60))
+            .get(":1")
+            .copied()
+    };
+
+    // a display that stops responding counts as an error right away, and
+    // gets the latest contents once it's retried
+    x11.fail(1);
+    wayland.copy(text("while failing"));
+    sync.wait_until("x11 is degraded", || errors() == Some(1));
+    assert_ne!(text("while failing"), x11.contents());
+    sync.clock.advance(DEGRADED_RETRY_INTERVAL);
+    sync.wait_until("x11 is retried", || x11.contents() == text("while failing"));
+
+    // what was copied in a display while it was degraded is synced once it's
+    // back, instead of being overwritten
+    x11.fail(1);
+    x11.copy(text("while degraded"));
+    sync.eventually("x11 is synced after recovering", || {
+        wayland.contents() == text("while degraded")
+    });
+    assert_eq!(Some(2), errors());
+    sync.stop();
+}
+
+#[test]
+fn secrets_expire() {
+    use crate::mock::MockClipboard;
+
+    let wayland = MockClipboard::new("wayland-1").watchable();
+    let x11 = MockClipboard::new(":1");
+    let config = Config {
+        secret_policy: SecretPolicy::Expire,
+        ..test_config()
+    };
+    let expiry = config.secret_expiry();
+    let sync = TestSync::start(
+        vec![Box::new(wayland.clone()), Box::new(x11.clone())],
+        config,
+    );
+
+    let mut secret = ClipboardContent::from_text("hunter2");
+    secret.insert("x-kde-passwordManagerHint", b"secret".to_vec());
+    wayland.copy(secret.clone());
+    sync.wait_until("the secret is synced", || x11.contents() == secret);
+    sync.clock.advance(expiry - Duration::from_secs(1));
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(secret, x11.contents());
+
+    // it's cleared everywhere, including where it was copied
+    sync.clock.advance(Duration::from_secs(1));
+    sync.wait_until("the secret is cleared", || {
+        x11.contents().is_empty() && wayland.contents().is_empty()
+    });
+    sync.stop();
+}
+
+#[test]
+fn images_are_converted() {
+    use crate::mock::MockClipboard;
+
+    let wayland = MockClipboard::new("wayland-1").watchable();
+    let x11 = MockClipboard::new(":1");
+    let sync = TestSync::start(
+        vec![Box::new(wayland.clone()), Box::new(x11.clone())],
+        test_config(),
+    );
+
+    // images are synced right away, and offered in the other common formats
+    // once they're converted
+    let mut png = vec![];
+    image::DynamicImage::ImageRgba8(image::RgbaImage::new(1, 1))
+        .write_to(&mut png, image::ImageFormat::Png)
+        .unwrap();
+    let mut copied = ClipboardContent::new();
+    copied.insert("image/png", png);
+    wayland.copy(copied);
+    sync.wait_until("the image is converted", || {
+        x11.contents().get("image/bmp").is_some()
+    });
+    assert!(x11.writes().iter().any(|w| w.mime_types() == ["image/png"]));
+    sync.stop();
+}
+
+#[test]
+fn empty_groups_wait_for_displays() {
+    use crate::config::GroupConfig;
+
+    // every display is in a named group, so the default group is empty. it
+    // waits instead of failing, and syncs displays that start later.
+    let tmux_dir = std::env::temp_dir().join(format!("clipboard-sync-sync-{}", std::process::id()));
+    std::fs::create_dir_all(&tmux_dir).unwrap();
+    tmux::tmpdir::set(Some(tmux_dir.clone()));
+    let mut config = test_config();
+    config.groups.insert(
+        "work".to_string(),
+        GroupConfig {
+            displays: vec![":1".to_string()],
+        },
+    );
+    assert!(!belongs(":1", "default", &config));
+    let sync = TestSync::start(vec![], config);
+    sync.clock.advance(HEARTBEAT_INTERVAL * 2);
+    thread::sleep(Duration::from_millis(20));
+    assert!(!sync.thread.is_finished(), "an empty group stopped syncing");
+    let tmux = |args: &[&str]| {
+        std::process::Command::new("tmux")
+            .arg("-S")
+            .arg(tmux::socket_dir().join("test"))
+            .args(args)
+            .status()
+            .is_ok_and(|status| status.success())
+    };
+    if tmux(&[
+        "-f",
+        "/dev/null",
+        "new-session",
+        "-d",
+        ";",
+        "set-buffer",
+        "later",
+    ]) {
+        sync.eventually("the new display is synced", || {
+            sync.control.content("default", Selection::Clipboard)
+                == Some(ClipboardContent::from_text("later"))
+        });
+        sync.wait_until("the new display is listed", || {
+            sync.control.displays() == ["tmux-test"]
+        });
+        tmux(&["kill-server"]);
+    } else {
+        crate::mock::skip("the new display in an empty group", "tmux");
+    }
+    sync.stop();
+    let _ = std::fs::remove_dir_all(&tmux_dir);
 }
@@ -0,0 +1,130 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+
+use crate::control::Control;
+use crate::log;
+
+/// How often to check the sync loops and update the status, at most.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tells systemd when the sync is ready, which displays are synced, and
+/// whether every sync loop is still running, when it runs as a `Type=notify`
+/// service. The watchdog is only kept alive while no sync loop is stuck, so a
+/// service with `WatchdogSec=` is restarted if one is.
+///
+/// When running forked, the child notifies systemd, so the service needs
+/// `NotifyAccess=all`.
+pub fn start(control: Arc<Control>) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let watchdog = watchdog_timeout();
+    let events = control.subscribe();
+    let notify = move || {
+        let mut ready = false;
+        let mut status = String::new();
+        let mut stalled = vec![];
+        loop {
+            // displays that are added or removed update the status right away
+            drop(events.recv_timeout(CHECK_INTERVAL));
+            let mut state = vec![];
+            if !ready && control.ready() {
+                ready = true;
+                state.push("READY=1".to_string());
+            }
+            let new_status = describe(&control);
+            if new_status != status {
+                state.push(format!("STATUS={new_status}"));
+                status = new_status;
+            }
+            if let Some(timeout) = watchdog.filter(|_| ready) {
+                let now_stalled = control.stalled(timeout / 2);
+                if now_stalled.is_empty() {
+                    state.push("WATCHDOG=1".to_string());
+                } else if now_stalled != stalled {
+                    log::error!(
+                        event = "stalled";
+                        "not notifying the watchdog, because these are not syncing: {now_stalled:?}"
+                    );
+                }
+                stalled = now_stalled;
+            }
+            if !state.is_empty() {
+                if let Err(err) = send(&socket, &state.join("\n")) {
+                    log::warning!("failed to notify systemd: {err}");
+                }
+            }
+        }
+    };
+    thread::Builder::new()
+        .name("systemd".to_string())
+        .spawn(notify)
+        .expect("failed to spawn systemd thread");
+}
+
+/// The status that systemctl shows.
+fn describe(control: &Control) -> String {
+    if control.paused() {
+        return "paused".to_string();
+    }
+    match control.displays() {
+        displays if displays.is_empty() => "no displays to sync".to_string(),
+        displays => format!("syncing {}", displays.join(", ")),
+    }
+}
+
+/// How long systemd waits for a watchdog notification before restarting the
+/// service, if it's enabled for this process or the parent that forked it.
+fn watchdog_timeout() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        let pid: i32 = pid.parse().ok()?;
+        let process = [nix::unistd::getpid(), nix::unistd::getppid()];
+        if !process.iter().any(|p| p.as_raw() == pid) {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// Sends a notification to the socket at `address`, which starts with @ if
+/// it's in the abstract namespace.
+fn send(address: &OsStr, state: &str) -> io::Result<()> {
+    let address = match address.as_bytes() {
+        [b'@', name @ ..] => SocketAddr::from_abstract_name(name)?,
+        path => SocketAddr::from_pathname(OsStr::from_bytes(path))?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[test]
+fn test() {
+    let dir = std::env::temp_dir().join(format!("clipboard-sync-notify-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("notify");
+    let systemd = UnixDatagram::bind(&path).unwrap();
+    send(path.as_os_str(), "READY=1\nSTATUS=syncing :0").unwrap();
+    let mut buf = [0; 64];
+    let n = systemd.recv(&mut buf).unwrap();
+    assert_eq!(b"READY=1\nSTATUS=syncing :0", &buf[..n]);
+
+    let name = format!("clipboard-sync-notify-{}", std::process::id());
+    let address = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+    let systemd = UnixDatagram::bind_addr(&address).unwrap();
+    send(OsStr::new(&format!("@{name}")), "WATCHDOG=1").unwrap();
+    let n = systemd.recv(&mut buf).unwrap();
+    assert_eq!(b"WATCHDOG=1", &buf[..n]);
+
+    let control = Control::new(None);
+    assert_eq!("no displays to sync", describe(&control));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
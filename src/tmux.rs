@@ -0,0 +1,177 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::clipboard::Clipboard;
+use crate::content::ClipboardContent;
+use crate::error::MyResult;
+use crate::mustatex::mustatex;
+
+// Used instead of $TMUX_TMPDIR when it's set, so tests can use their own
+// servers without changing the environment while other threads read it.
+mustatex! {
+    pub(crate) tmpdir: Option<PathBuf> = None;
+}
+
+/// tmux servers are synced as displays named "tmux-" followed by the name of
+/// their socket, like "tmux-default".
+pub const PREFIX: &str = "tmux-";
+
+/// The buffer that contents are copied to. Loading it again replaces it, so
+/// syncing doesn't fill up the buffer list.
+const BUFFER: &str = "clipboard-sync";
+
+/// The paste buffers of a tmux server. Contents are copied to a buffer of its
+/// own, and read from whichever buffer was added most recently, which is what
+/// tmux pastes. tmux only has text, and it has no primary selection.
+///
+/// The buffers are polled. tmux only reports their changes to clients that are
+/// attached to a session, and attaching one would change which of the user's
+/// sessions is attached and most recently used.
+#[derive(Debug)]
+pub struct TmuxClipboard {
+    name: String,
+    socket: PathBuf,
+}
+
+impl TmuxClipboard {
+    /// The server with the socket `name` in the socket directory, if it's
+    /// running.
+    pub fn new(name: &str) -> Option<Self> {
+        let clipboard = Self {
+            name: name.to_string(),
+            socket: socket_dir().join(name),
+        };
+        clipboard.running().then_some(clipboard)
+    }
+
+    fn running(&self) -> bool {
+        let status = self
+            .command()
+            .arg("list-sessions")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        matches!(status, Ok(status) if status.success())
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new("tmux");
+        command.arg("-S").arg(&self.socket);
+        command
+    }
+}
+
+impl Clipboard for TmuxClipboard {
+    fn display(&self) -> String {
+        format!("{PREFIX}{}", self.name)
+    }
+
+    fn get(&self) -> MyResult<ClipboardContent> {
+        let out = self.command().arg("show-buffer").output()?;
+        // show-buffer fails when there are no buffers
+        if !out.status.success() || out.stdout.is_empty() {
+            return Ok(ClipboardContent::new());
+        }
+        Ok(ClipboardContent::from_text(&String::from_utf8_lossy(
+            &out.stdout,
+        )))
+    }
+
+    fn set(&self, value: &ClipboardContent) -> MyResult<()> {
+        let Some(text) = value.text().filter(|text| !text.is_empty()) else {
+            // fails if the buffer was already deleted, which is fine
+            self.command()
+                .args(["delete-buffer", "-b", BUFFER])
+                .stderr(Stdio::null())
+                .status()?;
+            return Ok(());
+        };
+        let mut child = self
+            .command()
+            .args(["load-buffer", "-b", BUFFER, "-"])
+            .stdin(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        child.wait()?;
+
+        Ok(())
+    }
+}
+
+/// Where tmux creates the sockets of the servers for this user:
+/// $TMUX_TMPDIR/tmux-UID, where TMUX_TMPDIR defaults to /tmp.
+pub fn socket_dir() -> PathBuf {
+    let tmp = tmpdir::get().clone().unwrap_or_else(|| {
+        std::env::var_os("TMUX_TMPDIR")
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or_else(|| "/tmp".into())
+            .into()
+    });
+    tmp.join(format!("tmux-{}", nix::unistd::getuid()))
+}
+
+/// The names of the sockets in the socket directory, some of which may belong
+/// to servers that are no longer running.
+pub fn sockets() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(socket_dir()) else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_socket(name))
+        .collect()
+}
+
+/// Whether a file in the socket directory can be a server's socket. tmux
+/// creates a lock file next to the socket while a server starts.
+pub fn is_socket(file_name: &str) -> bool {
+    !file_name.ends_with(".lock")
+}
+
+#[test]
+fn test() {
+    use std::sync::mpsc;
+
+    let dir = std::env::temp_dir().join(format!("clipboard-sync-tmux-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let clipboard = TmuxClipboard {
+        name: "test".to_string(),
+        socket: dir.join("test"),
+    };
+    let started = clipboard
+        .command()
+        .args(["-f", "/dev/null", "new-session", "-d"])
+        .status();
+    if !matches!(started, Ok(status) if status.success()) {
+        crate::mock::skip("the tmux test", "tmux");
+        return;
+    }
+    assert!(clipboard.running());
+    assert_eq!("tmux-test", clipboard.display());
+    assert!(clipboard.get().unwrap().is_empty());
+
+    // attaching a client would change the state of the user's sessions
+    let (notify, _changes) = mpsc::channel();
+    assert!(!clipboard.watch(notify).unwrap());
+
+    clipboard
+        .set(&ClipboardContent::from_text("synced"))
+        .unwrap();
+    assert_eq!(Some("synced".to_string()), clipboard.get().unwrap().text());
+
+    // a yank in copy mode adds a buffer of its own
+    let yanked = clipboard.command().args(["set-buffer", "yanked"]).status();
+    assert!(yanked.unwrap().success());
+    assert_eq!(Some("yanked".to_string()), clipboard.get().unwrap().text());
+
+    clipboard.set(&ClipboardContent::new()).unwrap();
+    let buffers = clipboard.command().arg("list-buffers").output().unwrap();
+    assert!(!String::from_utf8_lossy(&buffers.stdout).contains(BUFFER));
+
+    clipboard.command().arg("kill-server").status().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
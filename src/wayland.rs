@@ -0,0 +1,414 @@
+use chrono::Local;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::sync::mpsc::{sync_channel, Sender};
+use std::{cell::RefCell, rc::Rc, thread};
+use wayland_client::{
+    global_filter, protocol::wl_seat::WlSeat, Display, EventQueue, GlobalManager, Main,
+};
+use wayland_protocols::wlr::unstable::data_control::v1::client::{
+    zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+    zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_offer_v1::{self, ZwlrDataControlOfferV1},
+    zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
+};
+
+use crate::clipboard::Selection;
+use crate::content::{readable_mime_types, ClipboardContent};
+use crate::error::{MyError, MyResult};
+use crate::log;
+
+/// A connection to a single wayland display, which is chosen by name rather
+/// than through WAYLAND_DISPLAY, so any number of displays can be used at once
+/// from any thread. The name is either a socket in XDG_RUNTIME_DIR, like
+/// "wayland-1", or an absolute path to a socket.
+struct DataControl {
+    queue: EventQueue,
+    manager: Main<ZwlrDataControlManagerV1>,
+    seats: Vec<Main<WlSeat>>,
+}
+
+impl DataControl {
+    fn connect(display: &str, selection: Selection) -> MyResult<Self> {
+        let connection = Display::connect_to_name(display)?;
+        let mut queue = connection.create_event_queue();
+        let attached = connection.attach(queue.token());
+
+        let seats = Rc::new(RefCell::new(Vec::<Main<WlSeat>>::new()));
+        let seats_cb = seats.clone();
+        let globals = GlobalManager::new_with_cb(
+            &attached,
+            global_filter!([WlSeat, 2, move |seat: Main<WlSeat>, _: DispatchData| {
+                seats_cb.borrow_mut().push(seat);
+            }]),
+        );
+        queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        // the primary selection was added in version 2
+        let version = match selection {
+            Selection::Clipboard => 1,
+            Selection::Primary => 2,
+        };
+        let manager = globals
+            .instantiate_exact::<ZwlrDataControlManagerV1>(version)
+            .map_err(|_| MyError::MissingWaylandProtocol {
+                name: "zwlr_data_control_manager_v1",
+                version,
+            })?;
+        let seats = seats.take();
+
+        Ok(Self {
+            queue,
+            manager,
+            seats,
+        })
+    }
+
+    fn roundtrip(&mut self) -> MyResult<()> {
+        self.queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+        Ok(())
+    }
+}
+
+/// Reads the selection from the first seat of the display.
+pub fn get(display: &str, selection: Selection) -> MyResult<ClipboardContent> {
+    let mut control = DataControl::connect(display, selection)?;
+    let Some(seat) = control.seats.first() else {
+        return Ok(ClipboardContent::new());
+    };
+    let current = Rc::new(RefCell::new(None));
+    let device = control.manager.get_data_device(seat);
+    let current_cb = current.clone();
+    device.quick_assign(move |_, event, _| match event {
+        zwlr_data_control_device_v1::Event::DataOffer { id } => {
+            id.as_ref()
+                .user_data()
+                .set(|| RefCell::new(Vec::<String>::new()));
+            id.quick_assign(|offer, event, _| {
+                if let zwlr_data_control_offer_v1::Event::Offer { mime_type } = event {
+                    if let Some(offered) = offer.as_ref().user_data().get::<RefCell<Vec<String>>>()
+                    {
+                        offered.borrow_mut().push(mime_type);
+                    }
+                }
+            });
+        }
+        zwlr_data_control_device_v1::Event::Selection { id }
+            if selection == Selection::Clipboard =>
+        {
+            replace_offer(&current_cb, id)
+        }
+        zwlr_data_control_device_v1::Event::PrimarySelection { id }
+            if selection == Selection::Primary =>
+        {
+            replace_offer(&current_cb, id)
+        }
+        zwlr_data_control_device_v1::Event::Selection { id: Some(other) }
+        | zwlr_data_control_device_v1::Event::PrimarySelection { id: Some(other) } => {
+            other.destroy()
+        }
+        _ => {}
+    });
+    control.roundtrip()?;
+
+    let mut content = ClipboardContent::new();
+    let offer = current.take();
+    if let Some(offer) = &offer {
+        let offered = offer
+            .as_ref()
+            .user_data()
+            .get::<RefCell<Vec<String>>>()
+            .map(|offered| offered.borrow().clone())
+            .unwrap_or_default();
+        for (mime_type, store_as) in readable_mime_types(&offered) {
+            let (read, write) = nix::unistd::pipe()?;
+            // SAFETY: the pipe's file descriptors are new and owned by nothing else
+            let (mut read, write) = unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) };
+            offer.receive(mime_type, std::os::unix::io::AsRawFd::as_raw_fd(&write));
+            drop(write);
+            control.roundtrip()?;
+            let mut data = vec![];
+            read.read_to_end(&mut data)?;
+            content.insert(store_as, data);
+        }
+        offer.destroy();
+    }
+    device.destroy();
+    control.roundtrip()?;
+
+    Ok(content)
+}
+
+/// Replaces the selection on every seat of the display. Unless the content is
+/// empty, it's offered by a dedicated thread with its own connection, which
+/// serves the data to other clients until they replace the selection.
+pub fn set(display: &str, selection: Selection, sources: Vec<(String, Vec<u8>)>) -> MyResult<()> {
+    if sources.is_empty() {
+        let mut control = DataControl::connect(display, selection)?;
+        for seat in &control.seats {
+            let device = control.manager.get_data_device(seat);
+            match selection {
+                Selection::Clipboard => device.set_selection(None),
+                Selection::Primary => device.set_primary_selection(None),
+            }
+            device.destroy();
+        }
+        return control.roundtrip();
+    }
+
+    let (ready_tx, ready_rx) = sync_channel(1);
+    let display = display.to_string();
+    thread::spawn(move || {
+        let offer = match Offer::new(&display, selection, sources) {
+            Ok(offer) => {
+                drop(ready_tx.send(Ok(())));
+                offer
+            }
+            Err(err) => {
+                drop(ready_tx.send(Err(err)));
+                return;
+            }
+        };
+        match offer.serve() {
+            Ok(()) => log::trace!("stopped offering the {selection} selection on {display}"),
+            Err(err) => {
+                log::warning!("stopped offering the {selection} selection on {display}: {err}")
+            }
+        }
+    });
+
+    ready_rx
+        .recv()
+        .unwrap_or_else(|_| Err(MyError::WatcherDied))
+}
+
+/// Contents that are offered as the selection on every seat of a display.
+struct Offer {
+    control: DataControl,
+    devices: Vec<Main<ZwlrDataControlDeviceV1>>,
+    /// the number of seats that still have this selection
+    remaining: Rc<RefCell<usize>>,
+}
+
+impl Offer {
+    fn new(display: &str, selection: Selection, sources: Vec<(String, Vec<u8>)>) -> MyResult<Self> {
+        let mut control = DataControl::connect(display, selection)?;
+        if control.seats.is_empty() {
+            return Err(MyError::NoSeats);
+        }
+        let data = Rc::new(sources.into_iter().collect::<HashMap<_, _>>());
+        let remaining = Rc::new(RefCell::new(control.seats.len()));
+        let mut devices = vec![];
+        for seat in &control.seats {
+            let device = control.manager.get_data_device(seat);
+            // other clients' selections are not needed
+            device.quick_assign(|_, event, _| match event {
+                zwlr_data_control_device_v1::Event::DataOffer { id } => {
+                    id.quick_assign(|_, _, _| {})
+                }
+                zwlr_data_control_device_v1::Event::Selection { id: Some(offer) }
+                | zwlr_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                    offer.destroy()
+                }
+                _ => {}
+            });
+            let source = control.manager.create_data_source();
+            for mime_type in data.keys() {
+                source.offer(mime_type.clone());
+            }
+            let (data, remaining) = (data.clone(), remaining.clone());
+            source.quick_assign(move |source, event, _| send(&source, event, &data, &remaining));
+            match selection {
+                Selection::Clipboard => device.set_selection(Some(&source)),
+                Selection::Primary => device.set_primary_selection(Some(&source)),
+            }
+            devices.push(device);
+        }
+        control.roundtrip()?;
+
+        Ok(Self {
+            control,
+            devices,
+            remaining,
+        })
+    }
+
+    fn serve(mut self) -> MyResult<()> {
+        while *self.remaining.borrow() > 0 {
+            self.control.queue.dispatch(&mut (), |_, _, _| {})?;
+        }
+        for device in self.devices {
+            device.destroy();
+        }
+        self.control.roundtrip()
+    }
+}
+
+/// Writes the requested data to other clients, and stops offering it once the
+/// selection is replaced.
+fn send(
+    source: &Main<ZwlrDataControlSourceV1>,
+    event: zwlr_data_control_source_v1::Event,
+    data: &HashMap<String, Vec<u8>>,
+    remaining: &RefCell<usize>,
+) {
+    match event {
+        zwlr_data_control_source_v1::Event::Send { mime_type, fd } => {
+            // SAFETY: the compositor gave us ownership of the file descriptor
+            let mut file = unsafe { File::from_raw_fd(fd) };
+            if let Some(data) = data.get(&mime_type) {
+                if let Err(err) = file.write_all(data) {
+                    log::debug!("failed to send {mime_type} to another client: {err}");
+                }
+            }
+        }
+        zwlr_data_control_source_v1::Event::Cancelled => {
+            source.destroy();
+            *remaining.borrow_mut() -= 1;
+        }
+        _ => {}
+    }
+}
+
+fn replace_offer(
+    current: &RefCell<Option<ZwlrDataControlOfferV1>>,
+    offer: Option<ZwlrDataControlOfferV1>,
+) {
+    if let Some(old) = current.replace(offer) {
+        old.destroy();
+    }
+}
+
+/// Keeps a persistent connection to the wayland display and sends the display
+/// name to `notify` every time the selection changes on any seat. The connection is
+/// owned by a dedicated thread, which exits when the display goes away or the
+/// receiving end of `notify` is dropped.
+///
+/// Returns once the listener is registered with the compositor, so any setup
+/// errors are reported to the caller rather than lost in the thread.
+pub fn watch_selection(
+    display: &str,
+    selection: Selection,
+    notify: Sender<String>,
+) -> MyResult<()> {
+    let (ready_tx, ready_rx) = sync_channel(1);
+    let display = display.to_string();
+    thread::spawn(move || {
+        let listener = match SelectionListener::new(&display, selection, notify) {
+            Ok(listener) => {
+                drop(ready_tx.send(Ok(())));
+                listener
+            }
+            Err(err) => {
+                drop(ready_tx.send(Err(err)));
+                return;
+            }
+        };
+        match listener.run() {
+            Ok(()) => log::debug!("stopped watching {display} for {selection} changes"),
+            Err(err) => log::warning!("stopped watching {display} for {selection} changes: {err}"),
+        }
+    });
+
+    ready_rx
+        .recv()
+        .unwrap_or_else(|_| Err(MyError::WatcherDied))
+}
+
+struct SelectionListener {
+    control: DataControl,
+    devices: Vec<Main<ZwlrDataControlDeviceV1>>,
+    state: Rc<RefCell<ListenerState>>,
+}
+
+struct ListenerState {
+    display: String,
+    selection: Selection,
+    notify: Sender<String>,
+    /// the most recent offer, which is destroyed when it's replaced
+    offer: Option<ZwlrDataControlOfferV1>,
+    /// set when the notification channel is closed and the thread should exit
+    closed: bool,
+}
+
+impl SelectionListener {
+    fn new(display: &str, selection: Selection, notify: Sender<String>) -> MyResult<Self> {
+        let mut control = DataControl::connect(display, selection)?;
+
+        let state = Rc::new(RefCell::new(ListenerState {
+            display: display.to_string(),
+            selection,
+            notify,
+            offer: None,
+            closed: false,
+        }));
+        let devices = control
+            .seats
+            .iter()
+            .map(|seat| {
+                let device = control.manager.get_data_device(seat);
+                let state = state.clone();
+                device.quick_assign(move |_, event, _| state.borrow_mut().handle(event));
+                device
+            })
+            .collect::<Vec<_>>();
+        if devices.is_empty() {
+            return Err(MyError::NoSeats);
+        }
+        control.roundtrip()?;
+
+        Ok(Self {
+            control,
+            devices,
+            state,
+        })
+    }
+
+    fn run(mut self) -> MyResult<()> {
+        while !self.state.borrow().closed {
+            self.control.queue.dispatch(&mut (), |_, _, _| {})?;
+        }
+        for device in self.devices {
+            device.destroy();
+        }
+
+        Ok(())
+    }
+}
+
+impl ListenerState {
+    fn handle(&mut self, event: zwlr_data_control_device_v1::Event) {
+        match event {
+            zwlr_data_control_device_v1::Event::DataOffer { id } => {
+                // mime types are read at paste time, so the offer's own events are ignored
+                id.quick_assign(|_, _, _| {});
+            }
+            zwlr_data_control_device_v1::Event::Selection { id } => {
+                self.selection_changed(Selection::Clipboard, id)
+            }
+            zwlr_data_control_device_v1::Event::PrimarySelection { id } => {
+                self.selection_changed(Selection::Primary, id)
+            }
+            zwlr_data_control_device_v1::Event::Finished => self.closed = true,
+            _ => {}
+        }
+    }
+
+    fn selection_changed(&mut self, selection: Selection, offer: Option<ZwlrDataControlOfferV1>) {
+        if selection != self.selection {
+            if let Some(offer) = offer {
+                offer.destroy();
+            }
+            return;
+        }
+        if let Some(old) = std::mem::replace(&mut self.offer, offer) {
+            old.destroy();
+        }
+        log::trace!("{selection} changed on {}", self.display);
+        if self.notify.send(self.display.clone()).is_err() {
+            self.closed = true;
+        }
+    }
+}
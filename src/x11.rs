@@ -0,0 +1,535 @@
+use chrono::Local;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{shutdown, Shutdown};
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use xcb::{xfixes, Atom, Window};
+
+use crate::clipboard::Selection;
+use crate::content::{readable_mime_types, text_aliases, ClipboardContent};
+use crate::error::{MyError, MyResult};
+use crate::log;
+
+/// How long to wait for the selection owner to respond to a conversion.
+const CONVERT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Size of each chunk when sending data incrementally (INCR).
+const INCR_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Reads a selection of an x11 display, and takes ownership of it
+/// to offer new contents. While owned, the contents are served to other
+/// clients by a dedicated thread with its own connection.
+pub struct X11Selection {
+    getter: Context,
+    /// held while reading, since concurrent reads would take each other's
+    /// events from the getter's connection
+    reading: Mutex<()>,
+    owner: Arc<Context>,
+    offer: Arc<RwLock<Offer>>,
+}
+
+/// Everything that's served while this process owns the selection.
+#[derive(Default)]
+struct Offer {
+    content: ClipboardContent,
+    targets: HashMap<Atom, Vec<u8>>,
+}
+
+struct Context {
+    connection: xcb::Connection,
+    window: Window,
+    atoms: Atoms,
+}
+
+struct Atoms {
+    selection: Atom,
+    targets: Atom,
+    timestamp: Atom,
+    incr: Atom,
+    /// the property on our own window that selection data is transferred to
+    property: Atom,
+}
+
+impl Context {
+    fn new(display: &str, selection: Selection) -> MyResult<Self> {
+        let (connection, screen) = xcb::Connection::connect(Some(display))?;
+        let window = connection.generate_id();
+        let root = connection
+            .get_setup()
+            .roots()
+            .nth(screen as usize)
+            .ok_or(MyError::X11Connect(xcb::ConnError::ClosedInvalidScreen))?;
+        xcb::create_window(
+            &connection,
+            xcb::COPY_FROM_PARENT as u8,
+            window,
+            root.root(),
+            0,
+            0,
+            1,
+            1,
+            0,
+            xcb::WINDOW_CLASS_INPUT_OUTPUT as u16,
+            root.root_visual(),
+            &[(
+                xcb::CW_EVENT_MASK,
+                xcb::EVENT_MASK_STRUCTURE_NOTIFY | xcb::EVENT_MASK_PROPERTY_CHANGE,
+            )],
+        )
+        .request_check()?;
+        let atoms = Atoms {
+            selection: selection_atom(&connection, selection)?,
+            targets: intern(&connection, "TARGETS")?,
+            timestamp: intern(&connection, "TIMESTAMP")?,
+            incr: intern(&connection, "INCR")?,
+            property: intern(&connection, "CLIPBOARD_SYNC")?,
+        };
+
+        Ok(Self {
+            connection,
+            window,
+            atoms,
+        })
+    }
+
+    /// Waits for the next event that satisfies `pick`, discarding others.
+    fn wait_for<T>(
+        &self,
+        deadline: Instant,
+        mut pick: impl FnMut(&xcb::GenericEvent) -> Option<T>,
+    ) -> MyResult<T> {
+        loop {
+            while let Some(event) = self.connection.poll_for_event() {
+                if let Some(picked) = pick(&event) {
+                    return Ok(picked);
+                }
+            }
+            self.connection.has_error()?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(MyError::X11Timeout);
+            }
+            let mut fds = [PollFd::new(self.connection.as_raw_fd(), PollFlags::POLLIN)];
+            poll(&mut fds, remaining.as_millis() as i32)?;
+        }
+    }
+}
+
+impl X11Selection {
+    pub fn new(display: &str, selection: Selection) -> MyResult<Self> {
+        let getter = Context::new(display, selection)?;
+        let owner = Arc::new(Context::new(display, selection)?);
+        let offer = Arc::new(RwLock::new(Offer::default()));
+        let (owner2, offer2) = (owner.clone(), offer.clone());
+        thread::spawn(move || serve(&owner2, &offer2));
+
+        Ok(Self {
+            getter,
+            reading: Mutex::new(()),
+            owner,
+            offer,
+        })
+    }
+
+    pub fn get(&self) -> MyResult<ClipboardContent> {
+        let _reading = self.reading.lock()?;
+        let owner = xcb::get_selection_owner(&self.getter.connection, self.getter.atoms.selection)
+            .get_reply()?
+            .owner();
+        if owner == xcb::NONE {
+            return Ok(ClipboardContent::new());
+        }
+        if owner == self.owner.window {
+            return Ok(self.offer.read()?.content.clone());
+        }
+        let targets = match self.convert(self.getter.atoms.targets)? {
+            Some(data) => data
+                .chunks_exact(4)
+                .map(|c| Atom::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+                .collect::<Vec<_>>(),
+            None => return Ok(ClipboardContent::new()),
+        };
+        let mut names = HashMap::new();
+        for atom in targets {
+            let reply = xcb::get_atom_name(&self.getter.connection, atom).get_reply()?;
+            names.insert(reply.name().to_string(), atom);
+        }
+        let offered = names.keys().cloned().collect::<Vec<_>>();
+        let mut content = ClipboardContent::new();
+        for (target, mime_type) in readable_mime_types(&offered) {
+            if let Some(data) = self.convert(names[&target])? {
+                content.insert(mime_type, data);
+            }
+        }
+
+        Ok(content)
+    }
+
+    pub fn set(&self, content: &ClipboardContent) -> MyResult<()> {
+        let connection = &self.owner.connection;
+        if content.is_empty() {
+            *self.offer.write()? = Offer::default();
+            xcb::set_selection_owner(
+                connection,
+                xcb::NONE,
+                self.owner.atoms.selection,
+                xcb::CURRENT_TIME,
+            )
+            .request_check()?;
+            return Ok(());
+        }
+        let mut targets = HashMap::new();
+        for (mime_type, data) in content.iter() {
+            targets.insert(intern(connection, mime_type)?, data.to_vec());
+        }
+        if let Some(text) = content.text() {
+            for target in text_aliases() {
+                targets
+                    .entry(intern(connection, target)?)
+                    .or_insert_with(|| text.clone().into_bytes());
+            }
+        }
+        *self.offer.write()? = Offer {
+            content: content.clone(),
+            targets,
+        };
+        xcb::set_selection_owner(
+            connection,
+            self.owner.window,
+            self.owner.atoms.selection,
+            xcb::CURRENT_TIME,
+        );
+        let owner = xcb::get_selection_owner(connection, self.owner.atoms.selection)
+            .get_reply()?
+            .owner();
+        if owner != self.owner.window {
+            return Err(MyError::X11NotOwner);
+        }
+
+        Ok(())
+    }
+
+    /// Asks the selection owner to convert the selection to `target`, and
+    /// returns the data, or None if the owner refused.
+    fn convert(&self, target: Atom) -> MyResult<Option<Vec<u8>>> {
+        let ctx = &self.getter;
+        xcb::convert_selection(
+            &ctx.connection,
+            ctx.window,
+            ctx.atoms.selection,
+            target,
+            ctx.atoms.property,
+            xcb::CURRENT_TIME,
+        );
+        ctx.connection.flush();
+        let property = ctx.wait_for(Instant::now() + CONVERT_TIMEOUT, |event| {
+            if event.response_type() & !0x80 != xcb::SELECTION_NOTIFY {
+                return None;
+            }
+            let event: &xcb::SelectionNotifyEvent = unsafe { xcb::cast_event(event) };
+            (event.selection() == ctx.atoms.selection && event.target() == target)
+                .then(|| event.property())
+        })?;
+        if property == xcb::NONE {
+            return Ok(None);
+        }
+        let reply = xcb::get_property(
+            &ctx.connection,
+            true,
+            ctx.window,
+            ctx.atoms.property,
+            xcb::ATOM_ANY,
+            0,
+            u32::MAX,
+        )
+        .get_reply()?;
+        if reply.type_() != ctx.atoms.incr {
+            return Ok(Some(reply.value::<u8>().to_vec()));
+        }
+
+        // the owner sends large data in chunks, each one after we delete the last
+        let mut data = vec![];
+        loop {
+            ctx.wait_for(Instant::now() + CONVERT_TIMEOUT, |event| {
+                if event.response_type() & !0x80 != xcb::PROPERTY_NOTIFY {
+                    return None;
+                }
+                let event: &xcb::PropertyNotifyEvent = unsafe { xcb::cast_event(event) };
+                (event.atom() == ctx.atoms.property
+                    && event.state() == xcb::PROPERTY_NEW_VALUE as u8)
+                    .then_some(())
+            })?;
+            let reply = xcb::get_property(
+                &ctx.connection,
+                true,
+                ctx.window,
+                ctx.atoms.property,
+                xcb::ATOM_ANY,
+                0,
+                u32::MAX,
+            )
+            .get_reply()?;
+            if reply.value_len() == 0 {
+                return Ok(Some(data));
+            }
+            data.extend_from_slice(reply.value::<u8>());
+        }
+    }
+}
+
+impl Drop for X11Selection {
+    /// Stops the thread that serves the selection.
+    fn drop(&mut self) {
+        xcb::destroy_window(&self.owner.connection, self.owner.window);
+        self.owner.connection.flush();
+    }
+}
+
+/// An incremental transfer to a requestor that is waiting for more chunks.
+struct IncrTransfer {
+    requestor: Window,
+    property: Atom,
+    target: Atom,
+    data: Vec<u8>,
+    position: usize,
+}
+
+/// Responds to other clients' requests for the selection while we own it.
+fn serve(ctx: &Context, offer: &RwLock<Offer>) {
+    let max_length = ctx.connection.get_maximum_request_length() as usize * 4 - 24;
+    let mut transfers: HashMap<(Window, Atom), IncrTransfer> = HashMap::new();
+    while let Some(event) = ctx.connection.wait_for_event() {
+        match event.response_type() & !0x80 {
+            xcb::SELECTION_REQUEST => {
+                let event: &xcb::SelectionRequestEvent = unsafe { xcb::cast_event(&event) };
+                // obsolete clients may not specify a property
+                let property = match event.property() {
+                    xcb::NONE => event.target(),
+                    property => property,
+                };
+                let offer = match offer.read() {
+                    Ok(offer) => offer,
+                    Err(_) => return,
+                };
+                let accepted = if event.selection() != ctx.atoms.selection {
+                    false
+                } else if event.target() == ctx.atoms.targets {
+                    let mut targets = vec![ctx.atoms.targets, ctx.atoms.timestamp];
+                    targets.extend(offer.targets.keys());
+                    xcb::change_property(
+                        &ctx.connection,
+                        xcb::PROP_MODE_REPLACE as u8,
+                        event.requestor(),
+                        property,
+                        xcb::ATOM_ATOM,
+                        32,
+                        &targets,
+                    );
+                    true
+                } else if event.target() == ctx.atoms.timestamp {
+                    xcb::change_property(
+                        &ctx.connection,
+                        xcb::PROP_MODE_REPLACE as u8,
+                        event.requestor(),
+                        property,
+                        xcb::ATOM_INTEGER,
+                        32,
+                        &[event.time()],
+                    );
+                    true
+                } else if let Some(data) = offer.targets.get(&event.target()) {
+                    if data.len() < max_length {
+                        xcb::change_property(
+                            &ctx.connection,
+                            xcb::PROP_MODE_REPLACE as u8,
+                            event.requestor(),
+                            property,
+                            event.target(),
+                            8,
+                            data,
+                        );
+                    } else {
+                        xcb::change_window_attributes(
+                            &ctx.connection,
+                            event.requestor(),
+                            &[(xcb::CW_EVENT_MASK, xcb::EVENT_MASK_PROPERTY_CHANGE)],
+                        );
+                        xcb::change_property(
+                            &ctx.connection,
+                            xcb::PROP_MODE_REPLACE as u8,
+                            event.requestor(),
+                            property,
+                            ctx.atoms.incr,
+                            32,
+                            &[data.len() as u32],
+                        );
+                        transfers.insert(
+                            (event.requestor(), property),
+                            IncrTransfer {
+                                requestor: event.requestor(),
+                                property,
+                                target: event.target(),
+                                data: data.clone(),
+                                position: 0,
+                            },
+                        );
+                    }
+                    true
+                } else {
+                    false
+                };
+                xcb::send_event(
+                    &ctx.connection,
+                    false,
+                    event.requestor(),
+                    0,
+                    &xcb::SelectionNotifyEvent::new(
+                        event.time(),
+                        event.requestor(),
+                        event.selection(),
+                        event.target(),
+                        if accepted { property } else { xcb::NONE },
+                    ),
+                );
+                ctx.connection.flush();
+            }
+            xcb::PROPERTY_NOTIFY => {
+                let event: &xcb::PropertyNotifyEvent = unsafe { xcb::cast_event(&event) };
+                if event.state() != xcb::PROPERTY_DELETE as u8 {
+                    continue;
+                }
+                let key = (event.window(), event.atom());
+                let done = match transfers.get_mut(&key) {
+                    Some(transfer) => {
+                        let end =
+                            usize::min(transfer.position + INCR_CHUNK_SIZE, transfer.data.len());
+                        xcb::change_property(
+                            &ctx.connection,
+                            xcb::PROP_MODE_REPLACE as u8,
+                            transfer.requestor,
+                            transfer.property,
+                            transfer.target,
+                            8,
+                            &transfer.data[transfer.position..end],
+                        );
+                        let done = transfer.position == end;
+                        transfer.position = end;
+                        done
+                    }
+                    None => continue,
+                };
+                if done {
+                    transfers.remove(&key);
+                }
+                ctx.connection.flush();
+            }
+            xcb::SELECTION_CLEAR => {
+                if let Ok(mut offer) = offer.write() {
+                    *offer = Offer::default();
+                }
+            }
+            xcb::DESTROY_NOTIFY => {
+                let event: &xcb::DestroyNotifyEvent = unsafe { xcb::cast_event(&event) };
+                if event.window() == ctx.window {
+                    return;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn selection_atom(connection: &xcb::Connection, selection: Selection) -> MyResult<Atom> {
+    match selection {
+        Selection::Clipboard => intern(connection, "CLIPBOARD"),
+        Selection::Primary => Ok(xcb::ATOM_PRIMARY),
+    }
+}
+
+fn intern(connection: &xcb::Connection, name: &str) -> MyResult<Atom> {
+    Ok(xcb::intern_atom(connection, false, name)
+        .get_reply()?
+        .atom())
+}
+
+/// Subscribes to XFixes SelectionNotify events for the selection on the x11
+/// display and sends the display name to `notify` every time the
+/// selection's owner changes. The events are read by a dedicated thread on
+/// its own connection, which exits when the returned watcher is dropped, when
+/// the display goes away, or when the receiving end of `notify` is dropped.
+pub fn watch_selection(
+    display: &str,
+    selection: Selection,
+    notify: Sender<String>,
+) -> MyResult<SelectionWatcher> {
+    let (connection, screen) = xcb::Connection::connect(Some(display))?;
+    let first_event = connection
+        .get_extension_data(xfixes::id())
+        .filter(|ext| ext.present())
+        .ok_or(MyError::MissingX11Extension("XFIXES"))?
+        .first_event();
+    xfixes::query_version(&connection, 5, 0).get_reply()?;
+    let root = connection
+        .get_setup()
+        .roots()
+        .nth(screen as usize)
+        .ok_or(MyError::X11Connect(xcb::ConnError::ClosedInvalidScreen))?
+        .root();
+    xfixes::select_selection_input(
+        &connection,
+        root,
+        selection_atom(&connection, selection)?,
+        xfixes::SELECTION_EVENT_MASK_SET_SELECTION_OWNER
+            | xfixes::SELECTION_EVENT_MASK_SELECTION_WINDOW_DESTROY
+            | xfixes::SELECTION_EVENT_MASK_SELECTION_CLIENT_CLOSE,
+    )
+    .request_check()?;
+
+    let watcher = SelectionWatcher {
+        connection: Arc::new(connection),
+        stopped: Arc::new(AtomicBool::new(false)),
+    };
+    let (connection, stopped) = (watcher.connection.clone(), watcher.stopped.clone());
+    let display = display.to_string();
+    thread::spawn(move || {
+        while let Some(event) = connection.wait_for_event() {
+            if event.response_type() & !0x80 != first_event + xfixes::SELECTION_NOTIFY {
+                continue;
+            }
+            log::trace!("selection owner changed on {display}");
+            if notify.send(display.clone()).is_err() {
+                log::debug!("stopped watching {display} for selection changes");
+                return;
+            }
+        }
+        match connection.has_error() {
+            _ if stopped.load(Ordering::SeqCst) => {
+                log::debug!("stopped watching {display} for selection changes")
+            }
+            Ok(()) => log::warning!("stopped watching {display} for selection changes"),
+            Err(err) => log::warning!("stopped watching {display} for selection changes: {err}"),
+        }
+    });
+
+    Ok(watcher)
+}
+
+/// Stops the thread that watches a selection when it's dropped, by shutting
+/// down its connection, since it would otherwise wait for the next selection
+/// change.
+pub struct SelectionWatcher {
+    connection: Arc<xcb::Connection>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for SelectionWatcher {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = shutdown(self.connection.as_raw_fd(), Shutdown::Both);
+    }
+}
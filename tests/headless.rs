@@ -0,0 +1,315 @@
+//! Runs clipboard-sync against real x11 servers and wayland compositors that
+//! need no screen or GPU: Xvfb, and sway with the headless wlroots backend.
+//! Each test is skipped when the programs it needs are not installed, unless
+//! CLIPBOARD_SYNC_REQUIRE_TOOLS=1 is set, which makes it fail instead.
+//!
+//! Every test uses its own x11 display numbers and its own XDG_RUNTIME_DIR, and
+//! only syncs the displays it started, so the displays you are using are never
+//! touched.
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+fn x11_displays_are_synced() {
+    if !installed(&["Xvfb", "xclip"]) {
+        return;
+    }
+    let env = Env::new("x11");
+    let _x1 = xvfb(91);
+    let _x2 = xvfb(92);
+    let _daemon = env.start_daemon(&[":91", ":92"]);
+
+    xclip_copy(91, "copied in :91");
+    eventually(|| xclip_paste(92) == "copied in :91");
+    xclip_copy(92, "copied in :92");
+    eventually(|| xclip_paste(91) == "copied in :92");
+}
+
+#[test]
+fn sync_survives_a_display_that_stops() {
+    if !installed(&["Xvfb", "xclip"]) {
+        return;
+    }
+    let env = Env::new("recovery");
+    let _x1 = xvfb(93);
+    let _x2 = xvfb(94);
+    let x3 = xvfb(95);
+    let mut daemon = env.start_daemon(&[":93", ":94", ":95"]);
+
+    xclip_copy(93, "before");
+    eventually(|| xclip_paste(95) == "before");
+    drop(x3);
+    xclip_copy(94, "after");
+    eventually(|| xclip_paste(93) == "after");
+    assert!(daemon.0.try_wait().unwrap().is_none(), "the daemon exited");
+
+    // a display that starts again gets the current contents
+    let _x3 = xvfb(95);
+    eventually(|| xclip_paste(95) == "after");
+}
+
+#[test]
+fn wayland_is_synced_with_x11_and_xwayland_is_deduplicated() {
+    if !installed(&["Xvfb", "xclip", "sway", "Xwayland", "wl-copy", "wl-paste"]) {
+        return;
+    }
+    let env = Env::new("wayland");
+    let _x = xvfb(96);
+    let (_sway, wayland, xwayland) = env.start_sway();
+    let _daemon = env.start_daemon(&[":96", &wayland, &format!(":{xwayland}")]);
+
+    env.wl_copy(&wayland, "copied in wayland");
+    eventually(|| xclip_paste(96) == "copied in wayland");
+    eventually(|| xclip_paste(xwayland) == "copied in wayland");
+    xclip_copy(96, "copied in x11");
+    eventually(|| env.wl_paste(&wayland) == "copied in x11");
+
+    // xwayland shares the compositor's clipboard, so only one of them is synced
+    let list = env.control("list");
+    let response: serde_json::Value = serde_json::from_str(&list).unwrap();
+    let displays = response["groups"]["test"]["clipboard"].as_array().unwrap();
+    assert_eq!(2, displays.len(), "{list}");
+}
+
+/// A temporary directory used as XDG_RUNTIME_DIR and for the config, so the
+/// daemon, its control socket, and the compositors are isolated.
+struct Env {
+    dir: PathBuf,
+}
+
+impl Env {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("clipboard-sync-test-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        Self { dir }
+    }
+
+    fn command(&self, program: impl AsRef<std::ffi::OsStr>) -> Command {
+        let mut command = Command::new(program);
+        command
+            .env("XDG_RUNTIME_DIR", &self.dir)
+            .env("XDG_CONFIG_HOME", &self.dir)
+            .env("XDG_DATA_HOME", &self.dir)
+            .env_remove("WAYLAND_DISPLAY")
+            .env_remove("DISPLAY");
+        command
+    }
+
+    /// Runs clipboard-sync, syncing nothing but the `displays`.
+    fn start_daemon(&self, displays: &[&str]) -> Process {
+        let config = self.dir.join("config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                "run_forked = false\n\
+                 dbus = false\n\
+                 history_max_entries = 0\n\
+                 settle_delay_ms = 50\n\
+                 ignore_ungrouped = true\n\
+                 [groups.test]\n\
+                 displays = {displays:?}\n"
+            ),
+        )
+        .unwrap();
+        let daemon = Process(
+            self.command(env!("CARGO_BIN_EXE_clipboard-sync"))
+                .arg("--config")
+                .arg(&config)
+                .spawn()
+                .unwrap(),
+        );
+        eventually(|| self.dir.join("clipboard-sync.sock").exists());
+        daemon
+    }
+
+    /// Sends a command to the running daemon, and returns its response.
+    fn control(&self, command: &str) -> String {
+        let out = self
+            .command(env!("CARGO_BIN_EXE_clipboard-sync"))
+            .arg(command)
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    /// Starts a headless sway with xwayland, and returns it along with its
+    /// wayland display and the number of its xwayland display.
+    fn start_sway(&self) -> (Process, String, u8) {
+        let before = x11_displays();
+        let config = self.dir.join("sway.conf");
+        std::fs::write(&config, "xwayland force\n").unwrap();
+        let sway = Process(
+            self.command("sway")
+                .arg("--config")
+                .arg(&config)
+                .env("WLR_BACKENDS", "headless")
+                .env("WLR_RENDERER", "pixman")
+                .env("WLR_LIBINPUT_NO_DEVICES", "1")
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let mut wayland = None;
+        eventually(|| {
+            wayland = wayland_displays(&self.dir).pop();
+            wayland.is_some()
+        });
+        let mut xwayland = None;
+        eventually(|| {
+            xwayland = x11_displays().into_iter().find(|n| !before.contains(n));
+            xwayland.is_some()
+        });
+        (sway, wayland.unwrap(), xwayland.unwrap())
+    }
+
+    fn wl_copy(&self, display: &str, text: &str) {
+        let mut child = self
+            .command("wl-copy")
+            .env("WAYLAND_DISPLAY", display)
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(text.as_bytes())
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+    }
+
+    fn wl_paste(&self, display: &str) -> String {
+        let out = self
+            .command("wl-paste")
+            .args(["--no-newline"])
+            .env("WAYLAND_DISPLAY", display)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&out.stdout).into_owned()
+    }
+}
+
+impl Drop for Env {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A child process that is stopped when it's dropped. It's given the chance to
+/// clean up, since displays that are killed leave their sockets behind.
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let pid = Pid::from_raw(self.0.id() as i32);
+        let _ = signal::kill(pid, Signal::SIGTERM);
+        let _ = self.0.wait();
+    }
+}
+
+/// Starts an x11 server on display `n`, once its socket is ready.
+fn xvfb(n: u8) -> Process {
+    let xvfb = Command::new("Xvfb")
+        .arg(format!(":{n}"))
+        .args(["-nolisten", "tcp", "-screen", "0", "64x64x24"])
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let xvfb = Process(xvfb);
+    eventually(|| x11_displays().contains(&n));
+    xvfb
+}
+
+fn xclip_copy(display: u8, text: &str) {
+    // xclip keeps running in the background to serve the selection
+    let mut child = Command::new("xclip")
+        .args([
+            "-selection",
+            "clipboard",
+            "-display",
+            &format!(":{display}"),
+        ])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(text.as_bytes())
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+}
+
+fn xclip_paste(display: u8) -> String {
+    let Output { stdout, .. } = Command::new("xclip")
+        .args(["-selection", "clipboard", "-o"])
+        .args(["-display", &format!(":{display}")])
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    String::from_utf8_lossy(&stdout).into_owned()
+}
+
+/// The numbers of the x11 displays that have a socket.
+fn x11_displays() -> Vec<u8> {
+    let Ok(entries) = std::fs::read_dir("/tmp/.X11-unix") else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_str()?.strip_prefix('X')?.parse().ok())
+        .collect()
+}
+
+/// The names of the wayland displays in a runtime directory, like "wayland-1".
+fn wayland_displays(dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|name| name.starts_with("wayland-") && !name.ends_with(".lock"))
+        .collect()
+}
+
+/// Waits for the condition to become true, and fails if it doesn't.
+fn eventually(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        assert!(
+            Instant::now() < deadline,
+            "timed out waiting for a condition"
+        );
+        sleep(Duration::from_millis(50));
+    }
+}
+
+/// Whether every program is on the PATH. Prints which ones are missing, or
+/// fails the test if they're required.
+fn installed(programs: &[&str]) -> bool {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let missing = programs
+        .iter()
+        .filter(|program| !std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        if std::env::var_os("CLIPBOARD_SYNC_REQUIRE_TOOLS").is_some_and(|v| v == "1") {
+            panic!("these are required, but not installed: {missing:?}");
+        }
+        println!("skipping because these are not installed: {missing:?}");
+    }
+    missing.is_empty()
+}